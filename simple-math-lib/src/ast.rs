@@ -0,0 +1,556 @@
+use bigdecimal::BigDecimal;
+use calculator::{self, CalcError, CalcResult, Context};
+use num::BigInt;
+use parser::Token;
+use std::collections::HashMap;
+
+/// An expression as a tree, for tools that want to look at it (pretty-printing, simplifying, ...)
+/// instead of just calculating it
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Ast {
+	/// A number, like `2`
+	Num(BigDecimal),
+	/// A variable, like `x`
+	Var(String),
+	/// An operator between two expressions, like `1 + 2`
+	BinOp(Box<Ast>, Token, Box<Ast>),
+	/// An operator on one expression: `+x`, `-x`, `~x`, `!x`, `x!`, or `|x|` using `Token::Bar`
+	UnaryOp(Token, Box<Ast>),
+	/// A chain of comparisons, like `a < b <= c`, which is true if every comparison in it is
+	Compare(Box<Ast>, Vec<(Token, Ast)>),
+	/// A function call, like `sqrt(2)`
+	Call(String, Vec<Ast>),
+	/// A variable assignment, like `x = 2`
+	Assign(String, Box<Ast>),
+	/// A constant declaration, like `const k = 5`
+	Const(String, Box<Ast>),
+	/// A function definition, like `f = ($1 * 2)`.
+	/// The body is kept as tokens, the same way it's stored in the function map.
+	Define(String, Vec<Token>)
+}
+impl Ast {
+	/// Turns the tree back into tokens that `calculator::calculate` understands.
+	/// Every operator is wrapped in parentheses, so the order is kept without knowing precedence.
+	/// So is every assignment inside something else, so `(x = 3) + 1` doesn't become `x = 3 + 1`.
+	/// The value of an assignment is the one thing left unwrapped, since `y = (...)` defines a function.
+	pub fn to_tokens(&self) -> Vec<Token> {
+		let mut tokens = Vec::new();
+		match *self {
+			Ast::Assign(..) |
+			Ast::Const(..) |
+			Ast::Define(..) => self.push_assignment(&mut tokens),
+			_ => self.push_tokens(&mut tokens)
+		}
+		tokens
+	}
+	fn push_tokens(&self, tokens: &mut Vec<Token>) {
+		match *self {
+			Ast::Num(ref num) => tokens.push(Token::Num(num.clone())),
+			Ast::Var(ref name) => tokens.push(Token::VarGet(name.clone())),
+			Ast::BinOp(ref expr1, ref op, ref expr2) => {
+				tokens.push(Token::ParenOpen);
+				expr1.push_tokens(tokens);
+				tokens.push(op.clone());
+				expr2.push_tokens(tokens);
+				tokens.push(Token::ParenClose);
+			},
+			Ast::Compare(ref expr1, ref chain) => {
+				tokens.push(Token::ParenOpen);
+				expr1.push_tokens(tokens);
+				for (op, expr) in chain {
+					tokens.push(op.clone());
+					expr.push_tokens(tokens);
+				}
+				tokens.push(Token::ParenClose);
+			},
+			Ast::UnaryOp(Token::Bar, ref expr) => {
+				tokens.push(Token::Bar);
+				expr.push_tokens(tokens);
+				tokens.push(Token::Bar);
+			},
+			Ast::UnaryOp(Token::Factorial, ref expr) => {
+				tokens.push(Token::ParenOpen);
+				expr.push_tokens(tokens);
+				tokens.push(Token::ParenClose);
+				tokens.push(Token::Factorial);
+			},
+			Ast::UnaryOp(ref op, ref expr) => {
+				tokens.push(op.clone());
+				tokens.push(Token::ParenOpen);
+				expr.push_tokens(tokens);
+				tokens.push(Token::ParenClose);
+			},
+			Ast::Call(ref name, ref args) => {
+				tokens.push(Token::BlockName(name.clone()));
+				tokens.push(Token::ParenOpen);
+				for (i, arg) in args.iter().enumerate() {
+					if i != 0 {
+						tokens.push(Token::Separator);
+					}
+					arg.push_tokens(tokens);
+				}
+				tokens.push(Token::ParenClose);
+			},
+			Ast::Assign(..) |
+			Ast::Const(..) |
+			Ast::Define(..) => {
+				tokens.push(Token::ParenOpen);
+				self.push_assignment(tokens);
+				tokens.push(Token::ParenClose);
+			}
+		}
+	}
+	fn push_assignment(&self, tokens: &mut Vec<Token>) {
+		match *self {
+			Ast::Assign(ref name, ref expr) => {
+				tokens.push(Token::VarAssign(name.clone()));
+				expr.push_value(tokens);
+			},
+			Ast::Const(ref name, ref expr) => {
+				tokens.push(Token::Const);
+				tokens.push(Token::VarAssign(name.clone()));
+				expr.push_value(tokens);
+			},
+			Ast::Define(ref name, ref body) => {
+				tokens.push(Token::VarAssign(name.clone()));
+				tokens.push(Token::ParenOpen);
+				tokens.extend(body.iter().cloned());
+			},
+			_ => unreachable!()
+		}
+	}
+	fn push_value(&self, tokens: &mut Vec<Token>) {
+		match *self {
+			Ast::BinOp(ref expr1, ref op, ref expr2) => {
+				expr1.push_tokens(tokens);
+				tokens.push(op.clone());
+				expr2.push_tokens(tokens);
+			},
+			Ast::Compare(ref expr1, ref chain) => {
+				expr1.push_tokens(tokens);
+				for (op, expr) in chain {
+					tokens.push(op.clone());
+					expr.push_tokens(tokens);
+				}
+			},
+			Ast::Assign(..) |
+			Ast::Const(..) |
+			Ast::Define(..) => self.push_assignment(tokens),
+			_ => self.push_tokens(tokens)
+		}
+	}
+}
+
+/// Builds the tree for `tokens`, walking them the same way `calculator::calculate` does.
+/// Nothing is calculated, so unknown functions and variables are fine.
+pub fn parse_ast(tokens: Vec<Token>) -> CalcResult<Ast> {
+	let mut variables = HashMap::new();
+	let mut functions = HashMap::new();
+	let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+
+	let ast = parse(&mut context)?;
+	match context.next() {
+		Some(token) => Err(CalcError::ExpectedEOF(token, context.location())),
+		None => Ok(ast)
+	}
+}
+
+/// Folds the constant parts of `ast`, like `2 + 3` into `5`,
+/// and removes identities like `x + 0`, `x * 1` and `x - x`.
+/// Parts that fail to calculate (like `1/0`) are left alone, so the error still happens when calculating.
+/// Only literal `0` and `1` count as identities, so the scale of the result never changes.
+/// Divisions and roots are folded with the default `div_scale`, and `==` with the default `epsilon`.
+/// Assumes the expression can be calculated at all, since `x - x` becomes `0` without looking at `x`.
+pub fn simplify(ast: Ast) -> Ast {
+	match ast {
+		Ast::BinOp(expr1, op, expr2) => {
+			let expr1 = simplify(*expr1);
+			let expr2 = simplify(*expr2);
+
+			match op {
+				Token::Add if is_int(&expr1, 0) => return expr2,
+				Token::Add |
+				Token::Sub if is_int(&expr2, 0) => return expr1,
+				Token::Sub if expr1 == expr2 && is_pure(&expr1) => {
+					use num::Zero;
+					return Ast::Num(BigDecimal::zero());
+				},
+				Token::Mul if is_int(&expr1, 1) => return expr2,
+				Token::Mul |
+				Token::Div if is_int(&expr2, 1) => return expr1,
+				_ => {}
+			}
+
+			fold(bin_op(expr1, op, expr2))
+		},
+		Ast::Compare(expr1, chain) => {
+			let chain = chain.into_iter().map(|(op, expr)| (op, simplify(expr))).collect();
+			fold(Ast::Compare(Box::new(simplify(*expr1)), chain))
+		},
+		Ast::UnaryOp(op, expr) => fold(Ast::UnaryOp(op, Box::new(simplify(*expr)))),
+		Ast::Call(name, args) => fold(Ast::Call(name, args.into_iter().map(simplify).collect())),
+		Ast::Assign(name, expr) => Ast::Assign(name, Box::new(simplify(*expr))),
+		Ast::Const(name, expr) => Ast::Const(name, Box::new(simplify(*expr))),
+		ast => ast
+	}
+}
+/// Calculates `ast` if all its direct children are numbers
+fn fold(ast: Ast) -> Ast {
+	let constant = match ast {
+		Ast::BinOp(ref expr1, _, ref expr2) => is_num(expr1) && is_num(expr2),
+		Ast::Compare(ref expr1, ref chain) => is_num(expr1) && chain.iter().all(|(_, expr)| is_num(expr)),
+		Ast::UnaryOp(_, ref expr) => is_num(expr),
+		Ast::Call(_, ref args) => args.iter().all(is_num),
+		_ => false
+	};
+	if !constant {
+		return ast;
+	}
+
+	// Unknown variables and user functions fail here, which keeps them as they are
+	let mut variables = HashMap::new();
+	let mut functions = HashMap::new();
+	let result = {
+		let tokens = ast.to_tokens();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		calculator::calculate(&mut context)
+	};
+
+	match result {
+		Ok(num) => Ast::Num(num),
+		Err(_) => ast
+	}
+}
+fn is_num(ast: &Ast) -> bool {
+	matches!(*ast, Ast::Num(_))
+}
+fn is_int(ast: &Ast, value: i64) -> bool {
+	match *ast {
+		Ast::Num(ref num) => {
+			let (int, scale) = num.as_bigint_and_exponent();
+			scale == 0 && int == BigInt::from(value)
+		},
+		_ => false
+	}
+}
+/// Returns true if calculating `ast` can't change anything, so it gives the same result every time
+fn is_pure(ast: &Ast) -> bool {
+	match *ast {
+		Ast::Num(_) |
+		Ast::Var(_) => true,
+		Ast::BinOp(ref expr1, _, ref expr2) => is_pure(expr1) && is_pure(expr2),
+		Ast::Compare(ref expr1, ref chain) => is_pure(expr1) && chain.iter().all(|(_, expr)| is_pure(expr)),
+		Ast::UnaryOp(_, ref expr) => is_pure(expr),
+		_ => false
+	}
+}
+
+fn bin_op(expr1: Ast, op: Token, expr2: Ast) -> Ast {
+	Ast::BinOp(Box::new(expr1), op, Box::new(expr2))
+}
+
+fn parse<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	if context.level == u8::MAX {
+		return Err(CalcError::TooDeep);
+	}
+
+	let expr1 = parse_logical_or(context)?;
+
+	match context.tokens.peek() {
+		Some(&Token::ParenClose) |
+		Some(&Token::Separator) |
+		Some(&Token::Bar)
+		if context.level != 0 => Ok(expr1),
+		Some(&Token::Semicolon)
+		if context.level == 0 => Ok(expr1),
+
+		Some(_) => {
+			let token = context.next().unwrap();
+			Err(CalcError::ExpectedEOF(token, context.location()))
+		},
+		None => Ok(expr1)
+	}
+}
+// These two are also used by the calculator to step over the side of `&&` and `||` it doesn't calculate
+pub(crate) fn parse_logical_or<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr1 = parse_logical_and(context)?;
+
+	if let Some(&Token::LogicalOr) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_logical_or(context)?;
+		return Ok(bin_op(expr1, op, expr2));
+	}
+
+	Ok(expr1)
+}
+pub(crate) fn parse_logical_and<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr1 = parse_comparison(context)?;
+
+	if let Some(&Token::LogicalAnd) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_logical_and(context)?;
+		return Ok(bin_op(expr1, op, expr2));
+	}
+
+	Ok(expr1)
+}
+fn parse_comparison<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let mut expr1 = parse_level1(context)?;
+	let mut chain = Vec::new();
+
+	while let Some(&Token::Equal) |
+			Some(&Token::NotEqual) |
+			Some(&Token::Less) |
+			Some(&Token::LessEqual) |
+			Some(&Token::Greater) |
+			Some(&Token::GreaterEqual) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		chain.push((op, parse_level1(context)?));
+	}
+	if !chain.is_empty() {
+		expr1 = Ast::Compare(Box::new(expr1), chain);
+	}
+
+	Ok(expr1)
+}
+fn parse_level1<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr1 = parse_level2(context)?;
+
+	if let Some(&Token::Xor) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level1(context)?;
+		return Ok(bin_op(expr1, op, expr2));
+	}
+
+	Ok(expr1)
+}
+fn parse_level2<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr1 = parse_level3(context)?;
+
+	if let Some(&Token::Or) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level2(context)?;
+		return Ok(bin_op(expr1, op, expr2));
+	}
+
+	Ok(expr1)
+}
+fn parse_level3<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr1 = parse_level4(context)?;
+
+	if let Some(&Token::And) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level3(context)?;
+		return Ok(bin_op(expr1, op, expr2));
+	}
+
+	Ok(expr1)
+}
+fn parse_level4<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let mut expr1 = parse_level5(context)?;
+
+	while let Some(&Token::BitshiftLeft) |
+			Some(&Token::BitshiftRight) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level5(context)?;
+		expr1 = bin_op(expr1, op, expr2);
+	}
+
+	Ok(expr1)
+}
+fn parse_level5<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let mut expr1 = parse_level6(context)?;
+
+	while let Some(&Token::Add) |
+			Some(&Token::Sub) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level6(context)?;
+		expr1 = bin_op(expr1, op, expr2);
+	}
+
+	Ok(expr1)
+}
+fn parse_level6<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let mut expr1 = parse_level7(context)?;
+
+	while let Some(&Token::Mul) |
+			Some(&Token::Div) |
+			Some(&Token::IntDiv) |
+			Some(&Token::Mod) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = parse_level7(context)?;
+		expr1 = bin_op(expr1, op, expr2);
+	}
+
+	Ok(expr1)
+}
+fn parse_level7<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	let expr = parse_level8(context)?;
+	if let Some(&Token::Factorial) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		return Ok(Ast::UnaryOp(op, Box::new(expr)));
+	}
+	Ok(expr)
+}
+fn parse_level8<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	if let Some(&Token::Not) |
+			Some(&Token::LogicalNot) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr = parse_level8(context)?;
+		return Ok(Ast::UnaryOp(op, Box::new(expr)));
+	}
+
+	parse_level9(context, None)
+}
+fn parse_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option<String>) -> CalcResult<Ast> {
+	if let Some(&Token::ParenOpen) = context.tokens.peek() {
+		context.next();
+
+		let mut args = Vec::new();
+
+		if let Some(&Token::ParenClose) = context.tokens.peek() {
+		} else {
+			context.level += 1;
+
+			args.push(parse(context)?);
+
+			while let Some(&Token::Separator) = context.tokens.peek() {
+				context.next();
+				args.push(parse(context)?);
+			}
+
+			context.level -= 1;
+		}
+		if Some(Token::ParenClose) != context.next() {
+			return Err(CalcError::UnclosedParen);
+		}
+
+		if let Some(name) = name {
+			return Ok(Ast::Call(name, args));
+		}
+		if args.len() > 1 {
+			return Err(CalcError::IncorrectArguments(1, args.len()));
+		}
+
+		use num::Zero;
+		return Ok(args.pop().unwrap_or_else(|| Ast::Num(BigDecimal::zero())));
+	} else if name.is_none() {
+		if let Some(&Token::BlockName(_)) = context.tokens.peek() {
+			if let Some(Token::BlockName(name)) = context.next() {
+				return parse_level9(context, Some(name));
+			}
+		} else if let Some(&Token::Bar) = context.tokens.peek() {
+			context.next();
+
+			context.level += 1;
+			let expr = parse(context)?;
+			context.level -= 1;
+
+			if Some(Token::Bar) != context.next() {
+				return Err(CalcError::UnclosedBar);
+			}
+
+			return Ok(Ast::UnaryOp(Token::Bar, Box::new(expr)));
+		}
+	}
+
+	parse_number(context)
+}
+fn parse_number<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Ast> {
+	match context.next() {
+		Some(Token::Num(num)) => Ok(Ast::Num(num)),
+		Some(op @ Token::Add) |
+		Some(op @ Token::Sub) => Ok(Ast::UnaryOp(op, Box::new(parse_level9(context, None)?))),
+		Some(Token::VarAssign(name)) => {
+			if let Some(&Token::ParenOpen) = context.tokens.peek() {
+				context.next();
+				let body = calculator::read_function_body(context)?;
+				if calculator::ends_expression(context) {
+					return Ok(Ast::Define(name, body));
+				}
+
+				// The parenthesis is only the start of the value, like in `y = (x = 3) + 1`
+				let mut tokens = vec![Token::ParenOpen];
+				tokens.extend(body);
+				tokens.extend(calculator::read_rest(context).0);
+				Ok(Ast::Assign(name, Box::new(parse_ast(tokens)?)))
+			} else {
+				Ok(Ast::Assign(name, Box::new(parse(context)?)))
+			}
+		},
+		Some(Token::Const) => match context.next() {
+			Some(Token::VarAssign(name)) => Ok(Ast::Const(name, Box::new(parse(context)?))),
+			_ => Err(CalcError::InvalidSyntax(context.location()))
+		},
+		Some(Token::VarGet(name)) => Ok(Ast::Var(name)),
+		_ => Err(CalcError::InvalidSyntax(context.location()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parser;
+
+	fn ast(input: &str) -> Ast {
+		parse_ast(parser::parse(input).unwrap()).unwrap()
+	}
+	fn num(num: &str) -> Box<Ast> {
+		Box::new(Ast::Num(num.parse().unwrap()))
+	}
+
+	#[test]
+	fn parenthesis_starting_a_value() {
+		assert_eq!(ast("y = (x = 3) + 1"), Ast::Assign("y".to_string(), Box::new(Ast::BinOp(
+			Box::new(Ast::Assign("x".to_string(), num("3"))), Token::Add, num("1")
+		))));
+		assert!(matches!(ast("f = ($1 * 2)"), Ast::Define(..)));
+	}
+	#[test]
+	fn round_trip_nested_assignment() {
+		let tokens = ast("(x = 3) + 1").to_tokens();
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let result = {
+			let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+			calculator::calculate_all(&mut context).unwrap()
+		};
+		assert_eq!(result, BigDecimal::from(4));
+		assert_eq!(variables["x"], BigDecimal::from(3));
+
+		for input in &["y = (x = 3) + 1", "y = 2 * 3 + 1", "y = x = 3"] {
+			let tree = ast(input);
+			assert_eq!(parse_ast(tree.to_tokens()).unwrap(), tree);
+		}
+	}
+	#[test]
+	fn left_associative() {
+		assert_eq!(ast("12 // 4 // 2"), Ast::BinOp(
+			Box::new(Ast::BinOp(num("12"), Token::IntDiv, num("4"))), Token::IntDiv, num("2")
+		));
+		assert_eq!(ast("10 - 2 - 3"), Ast::BinOp(
+			Box::new(Ast::BinOp(num("10"), Token::Sub, num("2"))), Token::Sub, num("3")
+		));
+	}
+	#[test]
+	fn tree() {
+		assert_eq!(ast("1 + 2 * 3"), Ast::BinOp(
+			num("1"), Token::Add, Box::new(Ast::BinOp(num("2"), Token::Mul, num("3")))
+		));
+		assert_eq!(ast("-x"), Ast::UnaryOp(Token::Sub, Box::new(Ast::Var("x".to_string()))));
+		assert_eq!(ast("|x|"), Ast::UnaryOp(Token::Bar, Box::new(Ast::Var("x".to_string()))));
+		// Unknown functions are fine, since nothing is calculated
+		assert_eq!(ast("foo(1, 2)"), Ast::Call("foo".to_string(), vec![*num("1"), *num("2")]));
+		assert!(matches!(parse_ast(parser::parse("1 +").unwrap()), Err(CalcError::InvalidSyntax(_))));
+	}
+	#[test]
+	fn simplify_folds() {
+		assert_eq!(simplify(ast("pow(2, 10)")), *num("1024"));
+		assert_eq!(simplify(ast("2 + 3 * x")), Ast::BinOp(num("2"), Token::Add, Box::new(Ast::BinOp(num("3"), Token::Mul, Box::new(Ast::Var("x".to_string()))))));
+		assert_eq!(simplify(ast("x * 1 + 0")), Ast::Var("x".to_string()));
+		assert_eq!(simplify(ast("x - x")), *num("0"));
+		assert_eq!(simplify(ast("(1 + 2) * x")), Ast::BinOp(num("3"), Token::Mul, Box::new(Ast::Var("x".to_string()))));
+		// Left for calculating to fail on
+		assert_eq!(simplify(ast("1 / 0")), ast("1 / 0"));
+	}
+}
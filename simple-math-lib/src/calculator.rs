@@ -1,39 +1,145 @@
 use bigdecimal::BigDecimal;
+use num::BigInt;
 use num::bigint::Sign;
 use parser::{Token, ParseError};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::Peekable;
 use std::{self, fmt, mem};
 
+/// How many digits after the decimal point divisions and approximations (like `sqrt`) are calculated to by default
+pub const PRECISION: i64 = 100;
+/// How many steps `sqrt` and `isqrt` may take by default before failing with `NoConvergence`.
+/// Far more than any number that fits in memory needs, so it only stops a bug from hanging forever.
+pub const MAX_SQRT_ITERATIONS: u64 = 10_000;
+/// How many results `Context::history` keeps by default
+pub const HISTORY_SIZE: usize = 100;
+/// How many bits a number may be shifted left (or rotated within) by default,
+/// since every bit takes memory
+pub const MAX_SHIFT: usize = 1_000_000;
+/// Functions written in the calculator's own language, that `Context::with_stdlib` defines:
+///
+/// - `double(x)` is `x * 2`
+/// - `half(x)` is `x / 2`
+/// - `cube(x)` is `x * x * x`
+/// - `sign(x)` is 1, 0 or -1 depending on whether `x` is positive, zero or negative
+/// - `lerp(a, b, t)` goes from `a` to `b` as `t` goes from 0 to 1
+pub const STDLIB: &str = "\
+	double = ($1 * 2);\
+	half = ($1 / 2);\
+	cube = ($1 * $1 * $1);\
+	sign = (($1 > 0) - ($1 < 0));\
+	lerp = ($1 + ($2 - $1) * $3)";
+/// The constants every `Context` knows, cut off after `PRECISION` decimals.
+/// They're read-only, but aren't put in the variables: a name is only looked up here
+/// when there's no variable with it, see `Context::lookup`.
+pub const CONSTANTS: [(&str, &str); 5] = [
+	("pi", "3.1415926535897932384626433832795028841971693993751058209749445923078164062862089986280348253421170679"),
+	("e", "2.7182818284590452353602874713526624977572470936999595749669676277240766303535475945713821785251664274"),
+	("tau", "6.2831853071795864769252867665590057683943387987502116419498891846156328125724179972560696506842341359"),
+	("phi", "1.6180339887498948482045868343656381177203091798057628621354486227052604628189024497072072041893911374"),
+	("sqrt2", "1.4142135623730950488016887242096980785696718753769480731766797379907324784621070388503875343276415727")
+];
+/// The names of every builtin function, which user functions can't be named after in any casing.
+///
+/// A sum over a range is `sum_range(i, 1, 5, i*i)` rather than `sum(i, 1, 5, i*i)`, and the same goes for `product`,
+/// because `sum` and `product` already take any amount of numbers to add up (or multiply).
+/// Writing it with `sum` fails with `CalcError::RangeInSum`, instead of adding up `i`, 1, 5 and `i*i`.
+pub const BUILTINS: [&str; 60] = [
+	"abs", "acos", "ans", "asin", "atan", "atan2", "avg", "between", "bigomega", "bitlen", "copysign", "cos", "cosh",
+	"dfact", "digitsum", "digroot", "dist", "exp", "exponent10", "fib", "frac", "gamma", "gcd", "hypot", "ilog", "in_range", "invmod", "is_prime",
+	"isqrt", "lcm", "ln", "mantissa", "max", "mean", "min", "mod", "normalize_angle", "omega", "pct_change", "popcount", "pow", "powmod", "product", "product_range", "rand", "recip",
+	"reverse", "rotl", "rotr", "round", "sin", "sinh", "sq", "sqrt", "sum", "sum_range", "tan", "tanh", "todeg", "torad"
+];
+/// The builtins calculated with a series or with an approximation of pi,
+/// which `calculate_verbose` warns about with `CalcWarning::Approximated`
+const APPROXIMATED: [&str; 15] = [
+	"acos", "asin", "atan", "atan2", "cos", "cosh", "exp", "ln", "normalize_angle", "sin", "sinh", "tan", "tanh", "todeg", "torad"
+];
+
+/// The result of a calculation
+pub type CalcResult<T> = Result<T, CalcError>;
+
+/// Where in the input an error is, counting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+	pub line: usize,
+	pub column: usize
+}
+impl fmt::Display for Position {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		// Most input is a single line, where the line number would only be noise
+		if self.line == 1 {
+			write!(f, "column {}", self.column)
+		} else {
+			write!(f, "line {}, column {}", self.line, self.column)
+		}
+	}
+}
+
 /// An error when calculating
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CalcError {
+	AssignmentNotAllowed,
+	BudgetExceeded,
 	DivideByZero,
-	ExpectedEOF(Token),
+	ExpectedEOF(Token, Position),
+	FractionalFactorial,
 	IncorrectArguments(usize, usize),
-	InvalidSyntax,
+	InfiniteRecursion(String),
+	InvalidSyntax(Position),
+	NoConvergence,
+	NoInverse,
 	NotAPositive,
-	NotAPrimitive(&'static str),
 	NotAWhole,
+	OutOfDomain(&'static str),
+	Overflow {
+		/// What the number was used for, like `&` or `fib`
+		operation: String,
+		/// The number that didn't fit
+		value: String,
+		/// The primitive it had to fit in, like `i64`
+		target: &'static str
+	},
 	ParseError(ParseError),
+	RangeInSum(String),
+	ReadOnly(String),
+	ReservedName(String),
+	ResultTooLarge,
 	SeparatorInDef,
+	ShiftTooLarge,
 	TooDeep,
+	UnclosedBar,
 	UnclosedParen,
-	UnknownFunction(String),
-	UnknownVariable(String)
+	UnknownFunction(String, Position),
+	UnknownVariable(String, Position)
 }
 impl fmt::Display for CalcError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		use std::error::Error;
 		match *self {
-			CalcError::ExpectedEOF(ref found) => write!(f, "Expected EOF, found {}", found),
+			CalcError::ExpectedEOF(ref found, pos) => write!(f, "Expected EOF, found {} at {}", found, pos),
+			CalcError::FractionalFactorial =>
+				write!(f, "You may only take the factorial of whole numbers\n\
+						   Hint: `gamma(x + 1)` is the factorial of a fraction"),
 			CalcError::IncorrectArguments(expected, received) =>
 				write!(f, "Incorrect amount of arguments (Expected {}, got {})", expected, received),
-			CalcError::NotAPrimitive(primitive) => write!(f, "Must fit in the range of an {} primitive", primitive),
+			CalcError::InfiniteRecursion(ref name) =>
+				write!(f, "Function \"{}\" calls itself with the same arguments, which would never end", name),
+			CalcError::OutOfDomain(domain) => write!(f, "Must be {}", domain),
+			CalcError::Overflow { ref operation, ref value, target } =>
+				write!(f, "{} in {} must fit in the range of an {} primitive", value, operation, target),
+			CalcError::InvalidSyntax(pos) => write!(f, "Invalid syntax at {}", pos),
 			CalcError::ParseError(ref error) => write!(f, "{}", error),
-			CalcError::UnknownFunction(ref name) =>
-				write!(f, "Unknown function \"{}\"\nHint: Cannot assume multiplication of variables because of ambiguity", name),
-			CalcError::UnknownVariable(ref name) => write!(f, "Unknown variable \"{}\"", name),
+			CalcError::RangeInSum(ref name) =>
+				write!(f, "\"{0}\" takes the numbers to combine, like {0}(1, 2, 3)\n\
+						   Hint: Use {0}_range(i, 1, 5, i*i) for a loop variable and bounds", name),
+			CalcError::ReadOnly(ref name) => write!(f, "Variable \"{}\" is read-only", name),
+			CalcError::ReservedName(ref name) =>
+				write!(f, "Cannot define function \"{}\", since there is a builtin with that name", name),
+			CalcError::UnknownFunction(ref name, pos) =>
+				write!(f, "Unknown function \"{}\" at {}\n\
+						   Hint: Cannot assume multiplication of variables because of ambiguity", name, pos),
+			CalcError::UnknownVariable(ref name, pos) => write!(f, "Unknown variable \"{}\" at {}", name, pos),
 			_ => write!(f, "{}", self.description())
 		}
 	}
@@ -41,42 +147,282 @@ impl fmt::Display for CalcError {
 impl std::error::Error for CalcError {
 	fn description(&self) -> &str {
 		match *self {
+			CalcError::AssignmentNotAllowed => "Cannot assign variables or define functions in read-only mode",
+			CalcError::BudgetExceeded => "Ran out of operations before the calculation was done",
 			CalcError::DivideByZero => "Cannot divide by zero",
-			CalcError::ExpectedEOF(_) => "Expected EOF",
+			CalcError::ExpectedEOF(..) => "Expected EOF",
+			CalcError::FractionalFactorial => "You may only take the factorial of whole numbers",
 			CalcError::IncorrectArguments(..) => "Incorrect amount of arguments",
-			CalcError::InvalidSyntax => "Invalid syntax",
+			CalcError::InfiniteRecursion(_) => "A function calls itself with the same arguments, which would never end",
+			CalcError::InvalidSyntax(_) => "Invalid syntax",
+			CalcError::NoConvergence => "The approximation didn't settle on a result in time",
+			CalcError::NoInverse => "There is no modular inverse, since the number and modulus share a factor",
 			CalcError::NotAPositive => "You may only do this on positive numbers",
-			CalcError::NotAPrimitive(_) => "You may only do this on a specific primitive types",
 			CalcError::NotAWhole => "You may only do this on whole numbers",
+			CalcError::OutOfDomain(_) => "The function isn't defined for that number",
+			CalcError::Overflow { .. } => "You may only do this on numbers that fit in a specific primitive type",
 			CalcError::ParseError(ref error)  => error.description(),
+			CalcError::RangeInSum(_) => "A sum or product over a range needs sum_range or product_range",
+			CalcError::ReadOnly(_) => "Cannot assign to a read-only variable",
+			CalcError::ReservedName(_) => "Cannot define a function with the name of a builtin",
+			CalcError::ResultTooLarge => "The result would have more digits than allowed",
 			CalcError::SeparatorInDef => "A function definition cannot have multiple arguments",
+			CalcError::ShiftTooLarge => "Cannot shift by that many bits, since the result would be too large",
 			CalcError::TooDeep => "Too many levels deep. This could be an issue with endless recursion.",
+			CalcError::UnclosedBar => "Unclosed absolute value bar",
 			CalcError::UnclosedParen => "Unclosed parenthensis",
-			CalcError::UnknownFunction(_) => "Unknown function",
-			CalcError::UnknownVariable(_) => "Unknown variable"
+			CalcError::UnknownFunction(..) => "Unknown function",
+			CalcError::UnknownVariable(..) => "Unknown variable"
+		}
+	}
+}
+
+impl From<ParseError> for CalcError {
+	fn from(error: ParseError) -> CalcError {
+		CalcError::ParseError(error)
+	}
+}
+
+/// What kind of error a `CalcError` is, so a program can handle them differently without matching every variant
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorCategory {
+	/// The input isn't a valid expression, like an unclosed parenthesis or the wrong amount of arguments
+	Syntax,
+	/// A function or operator was used on a number it isn't defined for, like dividing by zero
+	Domain,
+	/// A limit was hit, like `Context::op_budget` or `Context::max_digits`. A higher limit might work.
+	Resource,
+	/// Anything else, like an unknown variable or assigning to a read-only one
+	Unknown
+}
+impl CalcError {
+	/// Returns what kind of error this is
+	pub fn category(&self) -> ErrorCategory {
+		match *self {
+			CalcError::ExpectedEOF(..) |
+			CalcError::IncorrectArguments(..) |
+			CalcError::InvalidSyntax(_) |
+			CalcError::RangeInSum(_) |
+			CalcError::ParseError(_) |
+			CalcError::SeparatorInDef |
+			CalcError::UnclosedBar |
+			CalcError::UnclosedParen => ErrorCategory::Syntax,
+
+			CalcError::DivideByZero |
+			CalcError::FractionalFactorial |
+			CalcError::NoInverse |
+			CalcError::NotAPositive |
+			CalcError::NotAWhole |
+			CalcError::OutOfDomain(_) |
+			CalcError::Overflow { .. } => ErrorCategory::Domain,
+
+			CalcError::BudgetExceeded |
+			CalcError::NoConvergence |
+			CalcError::ResultTooLarge |
+			CalcError::ShiftTooLarge |
+			CalcError::TooDeep => ErrorCategory::Resource,
+
+			CalcError::AssignmentNotAllowed |
+			CalcError::InfiniteRecursion(_) |
+			CalcError::ReadOnly(_) |
+			CalcError::ReservedName(_) |
+			CalcError::UnknownFunction(..) |
+			CalcError::UnknownVariable(..) => ErrorCategory::Unknown
 		}
 	}
 }
 
+/// Converts to a primitive, failing with `NotAWhole` for fractions
+/// and `Overflow` for numbers out of the primitive's range
 macro_rules! to_primitive {
-	($expr:expr, $type:ident, $primitive:expr) => {
-		match $expr.$type() {
-			Some(primitive) => primitive,
-			None => return Err(CalcError::NotAPrimitive($primitive))
+	($expr:expr, $type:ident, $primitive:expr, $operation:expr) => {
+		{
+			require_whole(&$expr)?;
+			match $expr.$type() {
+				Some(primitive) => primitive,
+				None => return Err(CalcError::Overflow {
+					operation: String::from($operation),
+					value: $expr.to_string(),
+					target: $primitive
+				})
+			}
+		}
+	}
+}
+
+/// Records one operation in `context.trace`, if there is one.
+/// The operands are only cloned when tracing, so this costs nothing otherwise.
+macro_rules! trace {
+	($context:expr, $op:expr, [$($operand:expr),*], $result:expr) => {
+		if let Some(ref mut trace) = $context.trace {
+			let values = vec![$($operand.clone()),*];
+			let result = $result;
+			trace.push(TraceStep {
+				op: $op,
+				operands: values,
+				result: result.clone()
+			});
+			result
+		} else {
+			$result
+		}
+	}
+}
+
+/// One operation done while calculating, like `3 * 4 = 12`.
+/// Used to show step by step how a calculation got its result.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TraceStep {
+	/// The operator, like `Token::Mul`
+	pub op: Token,
+	/// The values the operator was used on, left to right
+	pub operands: Vec<BigDecimal>,
+	/// What the operation resulted in
+	pub result: BigDecimal
+}
+
+/// Something about a result that a frontend may want to tell the user, see `calculate_verbose`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CalcWarning {
+	/// A division or square root didn't end, so it was cut off after this many digits after the decimal point
+	Truncated(u64),
+	/// The builtin with this name was approximated, so the last few digits may be off
+	Approximated(String)
+}
+impl fmt::Display for CalcWarning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CalcWarning::Truncated(digits) => write!(f, "The result was cut off after {} decimals", digits),
+			CalcWarning::Approximated(ref name) => write!(f, "`{}` is approximated, so the last decimals may be off", name)
 		}
 	}
 }
+/// The result of `calculate_verbose`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Calculated {
+	/// The same result `calculate_all` would return
+	pub value: BigDecimal,
+	/// Everything to warn about, each only once, in the order they happened
+	pub warnings: Vec<CalcWarning>
+}
+
+/// How to round a number that's exactly halfway, like 2.5 to a whole number.
+/// Anything else rounds to whichever is closer.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RoundMode {
+	/// Away from zero, so 2.5 becomes 3 and -2.5 becomes -3
+	HalfUp,
+	/// To the closest even number, so 2.5 becomes 2 and 3.5 becomes 4.
+	/// Also called banker's rounding, since it doesn't round up more often than down.
+	HalfEven
+}
+
+/// Which way a chain of the same operator groups
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Associativity {
+	/// `a op b op c` is `(a op b) op c`, so `10 - 2 - 3` is 5
+	Left,
+	/// `a op b op c` is `a op (b op c)`, and `~~x` is `~(~x)`
+	Right,
+	/// `a < b < c` means `a < b && b < c`
+	Chained
+}
+/// How an operator is parsed, see `operator_info`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OpInfo {
+	/// How tightly the operator binds. Higher binds tighter, so `Mul` has a higher one than `Add`.
+	pub precedence: u8,
+	/// Which way a chain of the operator groups
+	pub associativity: Associativity,
+	/// If the operator takes one operand instead of two
+	pub unary: bool
+}
 
 /// A Context for `calculate` to pass around to all its sub-functions
 pub struct Context<'a, I: Iterator<Item = Token>> {
-	level: u8,
+	pub(crate) level: u8,
+	consumed: usize,
+	cache: HashMap<(String, Vec<BigDecimal>), BigDecimal>,
+	pow_cache: HashMap<(BigDecimal, BigDecimal), BigDecimal>,
+	/// The function calls that haven't returned yet, with the value of `changes` when they started
+	calls: Vec<((String, Vec<BigDecimal>), u64)>,
+	/// How many times a variable or function was set so far
+	changes: u64,
 
 	/// The tokens gotten by the parser
 	pub tokens: Peekable<I>,
+	/// The position of each token in the input, followed by the position of the end of the input.
+	/// Used to point errors at the offending token. Empty means every position is 0.
+	pub positions: Vec<usize>,
+	/// Where each line of the input after the first starts, from `parser::line_starts`,
+	/// so errors can tell the line and column of a position. Empty means the input is a single line.
+	pub line_starts: Vec<usize>,
 	/// A reference to a map of variables
 	pub variables: &'a mut HashMap<String, BigDecimal>,
-	/// A reference to a map of functions
-	pub functions: &'a mut HashMap<String, Vec<Token>>
+	/// A reference to a map of functions.
+	/// Calls are looked up when they happen, not when the calling function is defined,
+	/// so a function may call one that's defined after it (or redefined later) as long as it exists by the time of the call.
+	pub functions: &'a mut HashMap<String, Vec<Token>>,
+	/// How many more arithmetic operations may be done, or `None` for no limit.
+	/// Running out stops the calculation with `BudgetExceeded`,
+	/// so untrusted input can't keep a server busy forever.
+	pub op_budget: Option<u64>,
+	/// Names of variables that can't be assigned to, like the ones from `define_constant`
+	pub protected: HashSet<String>,
+	/// Whether to fail with `AssignmentNotAllowed` on any assignment, function definition or `const`,
+	/// so untrusted input can use the variables and functions but not change them
+	pub read_only: bool,
+	/// How many bits a number may be shifted left (or rotated within). Defaults to `MAX_SHIFT`.
+	/// Shifting further fails with `ShiftTooLarge` instead of trying to allocate a huge number.
+	pub max_shift: usize,
+	/// How many digits before the decimal point the result of a factorial, power or gamma may have,
+	/// or `None` for no limit. It's checked before calculating, so a huge result fails with `ResultTooLarge`
+	/// instead of taking all the memory. Unlike `op_budget`, this limits the size of a single number.
+	pub max_digits: Option<u64>,
+	/// How many steps `sqrt` and `isqrt` may take, see `MAX_SQRT_ITERATIONS` (the default).
+	/// Nothing else looks at this, since nothing else can keep going: `exp`, `ln` and the trigonometric functions
+	/// sum a series on whole numbers (see `GUARD_DIGITS`) after shrinking the argument, to below 1/256 for `exp`,
+	/// 1/3 for `ln`, 1/10 for `atan` and pi for sine and cosine, where the factorial takes over after the second term.
+	/// So each term is at most a fixed fraction of the one before, and the integer division makes one exactly 0,
+	/// which ends the series, after a number of terms that grows with the scale.
+	pub sqrt_max_iterations: u64,
+	/// How many digits after the decimal point divisions and roots are calculated to,
+	/// when they don't end before that. Defaults to `PRECISION`.
+	/// Divisions are rounded to that with `round_mode`, everything else is cut off.
+	/// Builtins like `sqrt`, `exp` and `atan` take an optional last argument to use another number of digits
+	/// for just that call, like `sqrt(2, 40)`.
+	pub div_scale: u64,
+	/// How far apart two numbers may be and still be equal for `==` and `!=`, like `sqrt(2)*sqrt(2) == 2`
+	/// which is off in the last decimal. Defaults to 0, which only lets exactly equal numbers be equal.
+	pub epsilon: BigDecimal,
+	/// How `round` and divisions round. Defaults to `RoundMode::HalfUp`.
+	pub round_mode: RoundMode,
+	/// Whether to remember the result of each user function call, so calling it again with the same arguments
+	/// doesn't calculate it again. Much faster for recursive functions,
+	/// but wrong if a function depends on variables that change during the calculation.
+	pub memoize: bool,
+	/// Whether user functions get their own copy of the variables, so assigning in a function body
+	/// (like `f = (y = $1 * 2)`) doesn't change the variables outside of it. Defaults to false.
+	pub isolate_functions: bool,
+	/// How many results of `pow` with a whole power to remember, so calculating `pow(2, k)` for the same `k`
+	/// again doesn't redo the work. Once that many are remembered, they're all forgotten to make room,
+	/// so it never takes more memory than this. Defaults to 0, which doesn't remember anything.
+	pub pow_cache_size: usize,
+	/// The character the parameters of user functions start with, like `$1`. Defaults to `parser::DEFAULT_PREFIX`.
+	/// It should be the same as the one given to `parser::parse_with_prefix`.
+	/// Functions defined with another prefix, like the ones from `with_stdlib`, won't see their parameters.
+	pub param_prefix: char,
+	/// Where to record every operation done, in the order they were done, or `None` to not record anything
+	pub trace: Option<&'a mut Vec<TraceStep>>,
+	/// Where to remember the results of the statements `calculate_all` calculated, most recent first,
+	/// or `None` to not remember them. Assignments aren't remembered.
+	/// `ans(1)` is the most recent result, `ans(2)` the one before that, and so on.
+	/// Keeping it between calculations, like in a REPL, lets later inputs use earlier results.
+	pub history: Option<&'a mut VecDeque<BigDecimal>>,
+	/// How many results `history` keeps, dropping the oldest ones. Defaults to `HISTORY_SIZE`.
+	pub history_size: usize,
+	last_error: Option<CalcError>,
+	warnings: Option<Vec<CalcWarning>>
 }
 impl<'a, I: Iterator<Item = Token>> Context<'a, I> {
 	pub fn new(
@@ -85,164 +431,564 @@ impl<'a, I: Iterator<Item = Token>> Context<'a, I> {
 		functions: &'a mut HashMap<String, Vec<Token>>
 		) -> Context<'a, I> {
 
-		Context {
+		use num::Zero;
+		let mut context = Context {
 			level: 0,
+			consumed: 0,
+			cache: HashMap::new(),
+			pow_cache: HashMap::new(),
+			calls: Vec::new(),
+			changes: 0,
 			tokens: tokens,
+			positions: Vec::new(),
+			line_starts: Vec::new(),
 			variables: variables,
-			functions: functions
+			functions: functions,
+			op_budget: None,
+			protected: HashSet::new(),
+			read_only: false,
+			max_shift: MAX_SHIFT,
+			max_digits: None,
+			sqrt_max_iterations: MAX_SQRT_ITERATIONS,
+			div_scale: PRECISION as u64,
+			epsilon: BigDecimal::zero(),
+			round_mode: RoundMode::HalfUp,
+			memoize: false,
+			isolate_functions: false,
+			pow_cache_size: 0,
+			param_prefix: ::parser::DEFAULT_PREFIX,
+			trace: None,
+			history: None,
+			history_size: HISTORY_SIZE,
+			last_error: None,
+			warnings: None
+		};
+		for &(name, _) in &CONSTANTS {
+			context.protected.insert(name.to_string());
 		}
+		context
 	}
-}
 
-/// Calculates the result in a recursive descent fashion
-pub fn calculate<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
-	if context.level == std::u8::MAX {
-		return Err(CalcError::TooDeep);
+	/// Same as `new`, but first defines the functions in `STDLIB`.
+	/// Functions that are already in `functions` are kept, and the library ones can be redefined like any other.
+	pub fn with_stdlib(
+		tokens: Peekable<I>,
+		variables: &'a mut HashMap<String, BigDecimal>,
+		functions: &'a mut HashMap<String, Vec<Token>>
+		) -> Context<'a, I> {
+
+		let mut stdlib = HashMap::new();
+		{
+			let mut variables = HashMap::new();
+			let tokens = ::parser::parse(STDLIB).unwrap();
+			let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut stdlib);
+			calculate_all(&mut context).unwrap();
+		}
+		for (name, body) in stdlib {
+			functions.entry(name).or_insert(body);
+		}
+
+		Context::new(tokens, variables, functions)
 	}
 
-	let expr1 = calc_level2(context)?;
+	/// Sets the variable `name` to `value` and makes it read-only, like the built-in `pi` and `e`.
+	/// This replaces a variable that already has that name.
+	pub fn define_constant(&mut self, name: &str, value: BigDecimal) {
+		self.variables.insert(name.to_string(), value);
+		self.protected.insert(name.to_string());
+		self.changes += 1;
+	}
+	/// Returns the value of the variable `name`, or of the constant in `CONSTANTS` if there is no such variable
+	pub fn lookup(&self, name: &str) -> Option<BigDecimal> {
+		self.variables.get(name).cloned().or_else(|| {
+			CONSTANTS.iter()
+				.find(|&&(constant, _)| constant == name)
+				.map(|&(_, value)| value.parse().unwrap())
+		})
+	}
+	/// Returns every constant and its value, sorted by name
+	pub fn constants(&self) -> Vec<(&str, BigDecimal)> {
+		let mut constants: Vec<_> = self.protected.iter()
+			.filter_map(|name| self.lookup(name).map(|value| (&**name, value)))
+			.collect();
+		constants.sort_by_key(|&(name, _)| name);
+		constants
+	}
 
-	if let Some(&Token::Xor) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calculate(context)?;
+	/// Copies the current variables and functions, so they can be brought back with `restore`.
+	/// Useful for rolling back an expression that failed halfway through its assignments.
+	pub fn snapshot(&self) -> (HashMap<String, BigDecimal>, HashMap<String, Vec<Token>>) {
+		(self.variables.clone(), self.functions.clone())
+	}
+	/// Replaces the variables and functions with ones from `snapshot`
+	pub fn restore(&mut self, (variables, functions): (HashMap<String, BigDecimal>, HashMap<String, Vec<Token>>)) {
+		*self.variables = variables;
+		*self.functions = functions;
+		self.cache.clear();
+	}
 
-		use num::ToPrimitive;
-		let primitive1 = to_primitive!(expr1, to_i64, "i64");
-		let primitive2 = to_primitive!(expr2, to_i64, "i64");
+	/// Returns the definition of the user function `name` as text, like `f = ($1 * 2)`,
+	/// or `None` if there is no such function. See `parser::tokens_to_string`.
+	pub fn function_source(&self, name: &str) -> Option<String> {
+		self.functions.get(name).map(|body| {
+			let mut tokens = vec![Token::VarAssign(name.to_string()), Token::ParenOpen];
+			tokens.extend(body.iter().cloned());
+			::parser::tokens_to_string(&tokens)
+		})
+	}
+
+	/// Returns the error of the last `calculate`, or `None` if it succeeded (or nothing was calculated yet).
+	/// Only the outermost `calculate` sets this, so it's the same error that was returned.
+	pub fn last_error(&self) -> Option<&CalcError> {
+		self.last_error.as_ref()
+	}
 
-		return Ok(BigDecimal::from(primitive1 ^ primitive2));
+	pub(crate) fn next(&mut self) -> Option<Token> {
+		self.consumed += 1;
+		self.tokens.next()
 	}
+	fn spend(&mut self, ops: u64) -> CalcResult<()> {
+		if let Some(ref mut budget) = self.op_budget {
+			if *budget < ops {
+				return Err(CalcError::BudgetExceeded);
+			}
+			*budget -= ops;
+		}
+		Ok(())
+	}
+	/// Spends one operation per unit in `ops`, like the multiplications of a factorial
+	fn spend_on(&mut self, ops: &BigDecimal) -> CalcResult<()> {
+		use num::{Signed, ToPrimitive};
+		let ops = ops.abs().to_u64().unwrap_or(u64::MAX);
+		self.spend(ops)
+	}
+	/// Fails with `ResultTooLarge` if a result with `digits` digits before the decimal point is too large for `max_digits`
+	fn check_digits(&self, digits: f64) -> CalcResult<()> {
+		match self.max_digits {
+			Some(max) if digits > max as f64 => Err(CalcError::ResultTooLarge),
+			_ => Ok(())
+		}
+	}
+	/// Records `warning` for `calculate_verbose`, unless it already was.
+	/// Does nothing for the other calculations, so check `self.warnings.is_some()` first if the check is costly.
+	fn warn(&mut self, warning: CalcWarning) {
+		if let Some(ref mut warnings) = self.warnings {
+			if !warnings.contains(&warning) {
+				warnings.push(warning);
+			}
+		}
+	}
+	/// Compares `a` and `b` with the tolerance in `epsilon`
+	fn equal(&self, a: &BigDecimal, b: &BigDecimal) -> bool {
+		use num::Signed;
+		a == b || (a - b).abs() < self.epsilon
+	}
+	/// Returns the position of the last consumed token
+	pub(crate) fn position(&self) -> usize {
+		let index = self.consumed.saturating_sub(1);
+		self.positions.get(index).or_else(|| self.positions.last()).cloned().unwrap_or(0)
+	}
+	/// Returns the line and column of the last consumed token, for an error
+	pub(crate) fn location(&self) -> Position {
+		self.locate(self.position())
+	}
+	/// Returns the line and column of `position`, which works like `positions`
+	pub(crate) fn locate(&self, position: usize) -> Position {
+		let line = self.line_starts.iter().take_while(|&&start| start <= position).count();
+		let start = if line == 0 { 0 } else { self.line_starts[line - 1] };
+		Position {
+			line: line + 1,
+			column: position - start + 1
+		}
+	}
+}
+
+/// Calculates all statements separated by `;`, left to right, like `x = 3; y = 4; x*y`.
+/// Returns the result of the last statement that isn't an assignment,
+/// or the result of the last assignment if there are only assignments.
+/// A trailing `;` is allowed.
+pub fn calculate_all<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let mut result = None;
+	let mut assignment_result = None;
+
+	loop {
+		let assignment = matches!(context.tokens.peek(), Some(&Token::VarAssign(_)) | Some(&Token::Const));
+		let val = calculate(context)?;
+		if assignment {
+			assignment_result = Some(val);
+		} else {
+			if let Some(ref mut history) = context.history {
+				history.push_front(val.clone());
+				history.truncate(context.history_size);
+			}
+			result = Some(val);
+		}
+
+		// `calculate` only stops early at a semicolon
+		if context.next().is_none() || context.tokens.peek().is_none() {
+			break;
+		}
+	}
+
+	Ok(result.or(assignment_result).unwrap())
+}
+/// Same as `calculate`, but as if `level` parentheses deep, like for an argument of a function call:
+/// a `)`, `,` or `|` ends the expression instead of being an error, and is left for the caller to take.
+/// `context` is put back at its own level afterwards. `Context::last_error` is only set at level 0.
+pub fn calculate_at_level<I: Iterator<Item = Token>>(context: &mut Context<I>, level: u8) -> CalcResult<BigDecimal> {
+	let outer = mem::replace(&mut context.level, level);
+	let result = calculate(context);
+	context.level = outer;
+	result
+}
+/// Calculates the result in a recursive descent fashion.
+/// Comparisons are the lowest level, and chain like in math:
+/// `a < b <= c` means `a < b && b <= c`, with `b` only calculated once.
+/// Every operand of a chain is calculated, even after a comparison that's false.
+/// A comparison results in 1 if true and 0 if false.
+pub fn calculate<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	// Parentheses and arguments call this too, one level deeper
+	if context.level != 0 {
+		return calc_expr(context);
+	}
+	let result = calc_expr(context);
+	if result.is_err() {
+		// The error skipped going back up the levels it went down
+		context.level = 0;
+	}
+	context.last_error = result.as_ref().err().cloned();
+	result
+}
+/// Same as `calculate_all`, but also returns what a frontend may want to warn about in any of the statements,
+/// like a division that was cut off after `Context::div_scale` digits or an approximated `sin`.
+/// Checking for those costs a bit extra, which is why `calculate_all` doesn't.
+pub fn calculate_verbose<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Calculated> {
+	let outer = context.warnings.replace(Vec::new());
+	let value = calculate_all(context);
+	let warnings = mem::replace(&mut context.warnings, outer).unwrap();
+	Ok(Calculated {
+		value: value?,
+		warnings
+	})
+}
+fn calc_expr<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	if context.level == u8::MAX {
+		return Err(CalcError::TooDeep);
+	}
+
+	let expr1 = calc_logical_or(context)?;
 
 	match context.tokens.peek() {
 		Some(&Token::ParenClose) |
-		Some(&Token::Separator)
+		Some(&Token::Separator) |
+		Some(&Token::Bar)
 		if context.level != 0 => Ok(expr1),
+		Some(&Token::Semicolon)
+		if context.level == 0 => Ok(expr1),
 
-		Some(_) => Err(CalcError::ExpectedEOF(context.tokens.next().unwrap())),
+		Some(_) => {
+			let token = context.next().unwrap();
+			Err(CalcError::ExpectedEOF(token, context.location()))
+		},
 		None => Ok(expr1)
 	}
 }
-fn calc_level2<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
+/// Returns how `token` is parsed when used as an operator, or None if it isn't one.
+/// `+` and `-` are described as the binary operators; as a sign they bind tighter than anything.
+pub fn operator_info(token: &Token) -> Option<OpInfo> {
+	// Keep in sync with the calc_* functions below, one precedence per function
+	let (precedence, associativity, unary) = match *token {
+		Token::LogicalOr => (1, Associativity::Right, false),
+		Token::LogicalAnd => (2, Associativity::Right, false),
+		Token::Equal |
+		Token::NotEqual |
+		Token::Less |
+		Token::LessEqual |
+		Token::Greater |
+		Token::GreaterEqual => (3, Associativity::Chained, false),
+		Token::Xor => (4, Associativity::Right, false),
+		Token::Or => (5, Associativity::Right, false),
+		Token::And => (6, Associativity::Right, false),
+		Token::BitshiftLeft |
+		Token::BitshiftRight => (7, Associativity::Left, false),
+		Token::Add |
+		Token::Sub => (8, Associativity::Left, false),
+		Token::Mul |
+		Token::Div |
+		Token::IntDiv |
+		Token::Mod => (9, Associativity::Left, false),
+		Token::Factorial => (10, Associativity::Left, true),
+		Token::Not |
+		Token::LogicalNot => (11, Associativity::Right, true),
+		_ => return None
+	};
+	Some(OpInfo {
+		precedence,
+		associativity,
+		unary
+	})
+}
+// `&&` and `||` treat anything but 0 as true, result in 1 or 0,
+// and skip the right side if the left one already decides the result, so `0 && 1/0` is just 0.
+// The skipped side is still parsed (with `ast`), so syntax errors in it aren't ignored.
+fn calc_logical_or<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let expr1 = calc_logical_and(context)?;
+
+	if let Some(&Token::LogicalOr) = context.tokens.peek() {
+		context.next();
+		context.spend(1)?;
+
+		use num::Zero;
+		if !expr1.is_zero() {
+			::ast::parse_logical_or(context)?;
+			return Ok(trace!(context, Token::LogicalOr, [expr1], BigDecimal::from(1)));
+		}
+		let expr2 = calc_logical_or(context)?;
+		let result = BigDecimal::from(!expr2.is_zero() as u8);
+		return Ok(trace!(context, Token::LogicalOr, [expr1, expr2], result));
+	}
+
+	Ok(expr1)
+}
+fn calc_logical_and<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let expr1 = calc_comparison(context)?;
+
+	if let Some(&Token::LogicalAnd) = context.tokens.peek() {
+		context.next();
+		context.spend(1)?;
+
+		use num::Zero;
+		if expr1.is_zero() {
+			::ast::parse_logical_and(context)?;
+			return Ok(trace!(context, Token::LogicalAnd, [expr1], BigDecimal::from(0)));
+		}
+		let expr2 = calc_logical_and(context)?;
+		let result = BigDecimal::from(!expr2.is_zero() as u8);
+		return Ok(trace!(context, Token::LogicalAnd, [expr1, expr2], result));
+	}
+
+	Ok(expr1)
+}
+fn calc_comparison<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let mut expr1 = calc_level1(context)?;
+	let mut all_hold = None;
+
+	while let Some(&Token::Equal) |
+			Some(&Token::NotEqual) |
+			Some(&Token::Less) |
+			Some(&Token::LessEqual) |
+			Some(&Token::Greater) |
+			Some(&Token::GreaterEqual) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = calc_level1(context)?;
+		context.spend(1)?;
+
+		let holds = match op {
+			Token::Equal => context.equal(&expr1, &expr2),
+			Token::NotEqual => !context.equal(&expr1, &expr2),
+			Token::Less => expr1 < expr2,
+			Token::LessEqual => expr1 <= expr2,
+			Token::Greater => expr1 > expr2,
+			Token::GreaterEqual => expr1 >= expr2,
+			_ => unreachable!()
+		};
+		trace!(context, op, [expr1, expr2], BigDecimal::from(holds as i64));
+
+		all_hold = Some(all_hold.unwrap_or(true) && holds);
+		expr1 = expr2;
+	}
+	if let Some(all_hold) = all_hold {
+		expr1 = BigDecimal::from(all_hold as i64);
+	}
+
+	Ok(expr1)
+}
+fn calc_level1<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let expr1 = calc_level2(context)?;
+
+	if let Some(&Token::Xor) = context.tokens.peek() {
+		context.next();
+		let expr2 = calc_level1(context)?;
+		context.spend(1)?;
+
+		use num::ToPrimitive;
+		let primitive1 = to_primitive!(expr1, to_i64, "i64", "^");
+		let primitive2 = to_primitive!(expr2, to_i64, "i64", "^");
+
+		return Ok(trace!(context, Token::Xor, [expr1, expr2], BigDecimal::from(primitive1 ^ primitive2)));
+	}
+
+	Ok(expr1)
+}
+fn calc_level2<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
 	let expr1 = calc_level3(context)?;
 
 	if let Some(&Token::Or) = context.tokens.peek() {
-		context.tokens.next();
+		context.next();
 		let expr2 = calc_level2(context)?;
+		context.spend(1)?;
 
 		use num::ToPrimitive;
-		let primitive1 = to_primitive!(expr1, to_i64, "i64");
-		let primitive2 = to_primitive!(expr2, to_i64, "i64");
+		let primitive1 = to_primitive!(expr1, to_i64, "i64", "|");
+		let primitive2 = to_primitive!(expr2, to_i64, "i64", "|");
 
-		return Ok(BigDecimal::from(primitive1 | primitive2));
+		return Ok(trace!(context, Token::Or, [expr1, expr2], BigDecimal::from(primitive1 | primitive2)));
 	}
 
 	Ok(expr1)
 }
-fn calc_level3<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
+fn calc_level3<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
 	let expr1 = calc_level4(context)?;
 
 	if let Some(&Token::And) = context.tokens.peek() {
-		context.tokens.next();
+		context.next();
 		let expr2 = calc_level3(context)?;
+		context.spend(1)?;
 
 		use num::ToPrimitive;
-		let primitive1 = to_primitive!(expr1, to_i64, "i64");
-		let primitive2 = to_primitive!(expr2, to_i64, "i64");
+		let primitive1 = to_primitive!(expr1, to_i64, "i64", "&");
+		let primitive2 = to_primitive!(expr2, to_i64, "i64", "&");
 
-		return Ok(BigDecimal::from(primitive1 & primitive2));
+		return Ok(trace!(context, Token::And, [expr1, expr2], BigDecimal::from(primitive1 & primitive2)));
 	}
 
 	Ok(expr1)
 }
-fn calc_level4<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
-	let expr1 = calc_level5(context)?;
+// Shifts and everything below them go left to right, like `10 - 2 - 3` being `(10 - 2) - 3`,
+// so they loop instead of recursing. The bitwise and logical operators above don't care about the order.
+fn calc_level4<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let mut expr1 = calc_level5(context)?;
 
 	use num::bigint::ToBigInt;
-	if let Some(&Token::BitshiftLeft) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level4(context)?;
+	while let Some(&Token::BitshiftLeft) |
+			Some(&Token::BitshiftRight) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = calc_level5(context)?;
+		context.spend(1)?;
 
 		use num::ToPrimitive;
-		let primitive2 = to_primitive!(expr2, to_usize, "usize");
-
-		require_whole(&expr1)?;
-		return Ok(BigDecimal::new(expr1.to_bigint().unwrap() << primitive2, 0));
-	} else if let Some(&Token::BitshiftRight) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level4(context)?;
+		let result = if op == Token::BitshiftLeft {
+			let primitive2 = to_primitive!(expr2, to_usize, "usize", "<<");
+			if primitive2 > context.max_shift {
+				return Err(CalcError::ShiftTooLarge);
+			}
 
-		use num::ToPrimitive;
-		let primitive2 = to_primitive!(expr2, to_usize, "usize");
+			require_whole(&expr1)?;
+			BigDecimal::new(expr1.to_bigint().unwrap() << primitive2, 0)
+		} else {
+			let primitive2 = to_primitive!(expr2, to_usize, "usize", ">>");
 
-		require_whole(&expr1)?;
-		return Ok(BigDecimal::new(expr1.to_bigint().unwrap() >> primitive2, 0));
+			require_whole(&expr1)?;
+			BigDecimal::new(expr1.to_bigint().unwrap() >> primitive2, 0)
+		};
+		expr1 = trace!(context, op, [expr1, expr2], result);
 	}
 
 	Ok(expr1)
 }
-fn calc_level5<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
-	let expr1 = calc_level6(context)?;
-
-	if let Some(&Token::Add) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level5(context)?;
+fn calc_level5<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let mut expr1 = calc_level6(context)?;
 
-		return Ok(expr1 + expr2);
-	} else if let Some(&Token::Sub) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level5(context)?;
+	while let Some(&Token::Add) |
+			Some(&Token::Sub) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = calc_level6(context)?;
+		context.spend(1)?;
 
-		return Ok(expr1 - expr2);
+		let result = if op == Token::Add { &expr1 + &expr2 } else { &expr1 - &expr2 };
+		expr1 = trace!(context, op, [expr1, expr2], result);
 	}
 
 	Ok(expr1)
 }
-fn calc_level6<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
-	let expr1 = calc_level7(context)?;
-
-	if let Some(&Token::Mul) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level6(context)?;
+fn calc_level6<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	let mut expr1 = calc_level7(context)?;
 
-		return Ok(expr1 * expr2);
-	} else if let Some(&Token::Div) = context.tokens.peek() {
-		context.tokens.next();
-		let expr2 = calc_level6(context)?;
+	while let Some(&Token::Mul) |
+			Some(&Token::Div) |
+			Some(&Token::IntDiv) |
+			Some(&Token::Mod) = context.tokens.peek() {
+		let op = context.next().unwrap();
+		let expr2 = calc_level7(context)?;
+		context.spend(1)?;
 
 		use num::Zero;
-		if expr2.is_zero() {
-			return Err(CalcError::DivideByZero);
-		}
-
-		return Ok(expr1 / expr2);
+		let result = match op {
+			Token::Mul => &expr1 * &expr2,
+			Token::Div => {
+				if expr2.is_zero() {
+					return Err(CalcError::DivideByZero);
+				}
+				let result = div_round(&expr1, &expr2, context.div_scale, context.round_mode);
+				if context.warnings.is_some() && &result * &expr2 != expr1 {
+					context.warn(CalcWarning::Truncated(context.div_scale));
+				}
+				result
+			},
+			Token::IntDiv => {
+				if expr2.is_zero() {
+					return Err(CalcError::DivideByZero);
+				}
+				floor_div(&expr1, &expr2)
+			},
+			Token::Mod => modulo(expr1.clone(), expr2.clone())?,
+			_ => unreachable!()
+		};
+		expr1 = trace!(context, op, [expr1, expr2], result);
 	}
 
 	Ok(expr1)
 }
-fn calc_level7<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
+fn calc_level7<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
 	let expr = calc_level8(context)?;
 	if let Some(&Token::Factorial) = context.tokens.peek() {
-		context.tokens.next();
+		context.next();
 
-		return factorial(expr, None);
+		context.spend_on(&expr)?;
+		context.check_digits(factorial_digits(&expr))?;
+		let result = trace!(context, Token::Factorial, [expr], factorial(expr, None)?);
+		return Ok(result);
 	}
 	Ok(expr)
 }
-fn calc_level8<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
+fn calc_level8<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
 	if let Some(&Token::Not) = context.tokens.peek() {
-		context.tokens.next();
+		context.next();
 		use num::ToPrimitive;
 		let expr = calc_level8(context)?;
-		let primitive = to_primitive!(expr, to_i64, "i64");
+		context.spend(1)?;
+		let primitive = to_primitive!(expr, to_i64, "i64", "~");
+
+		return Ok(trace!(context, Token::Not, [expr], BigDecimal::from(!primitive)));
+	} else if let Some(&Token::LogicalNot) = context.tokens.peek() {
+		context.next();
+		let expr = calc_level8(context)?;
+		context.spend(1)?;
 
-		return Ok(BigDecimal::from(!primitive));
+		// Unlike `~`, this works on any number: 0 becomes 1 and everything else 0
+		use num::Zero;
+		let result = BigDecimal::from(expr.is_zero() as u8);
+		return Ok(trace!(context, Token::LogicalNot, [expr], result));
 	}
 
 	Ok(calc_level9(context, None)?)
 }
-fn calc_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option<String>) -> Result<BigDecimal, CalcError> {
+fn calc_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option<String>) -> CalcResult<BigDecimal> {
+	let pos = context.position();
 	if let Some(&Token::ParenOpen) = context.tokens.peek() {
-		context.tokens.next();
+		context.next();
+
+		if let Some(ref name) = name {
+			let name = name.to_lowercase();
+			if name == "sum_range" || name == "product_range" {
+				context.spend(1)?;
+				return calc_aggregate(context, name == "product_range");
+			}
+			if name == "sum" || name == "product" {
+				context.spend(1)?;
+				return calc_variadic(context, name);
+			}
+		}
 
 		let mut args = Vec::new();
 
@@ -253,13 +999,13 @@ fn calc_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option
 			args.push(calculate(context)?);
 
 			while let Some(&Token::Separator) = context.tokens.peek() {
-				context.tokens.next();
+				context.next();
 				args.push(calculate(context)?);
 			}
 
 			context.level -= 1;
 		}
-		if Some(Token::ParenClose) != context.tokens.next() {
+		if Some(Token::ParenClose) != context.next() {
 			return Err(CalcError::UnclosedParen);
 		}
 
@@ -271,8 +1017,32 @@ fn calc_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option
 			}
 		}
 
+		// For builtins that calculate to `div_scale` digits, an extra last argument
+		// gives the digits to use instead, like `sqrt(2, 40)`
+		macro_rules! scale {
+			($expected:expr) => {
+				if args.len() == $expected + 1 {
+					let scale = args.pop().unwrap();
+					require_positive(&scale)?;
+					context.spend_on(&scale)?;
+					use num::ToPrimitive;
+					to_primitive!(scale, to_u64, "u64", "precision")
+				} else {
+					usage!($expected);
+					context.div_scale
+				}
+			}
+		}
+
 		if let Some(name) = name {
-			match &*name {
+			context.spend(1)?;
+			if APPROXIMATED.contains(&&*name.to_lowercase()) {
+				context.warn(CalcWarning::Approximated(name.to_lowercase()));
+			}
+			// Builtins ignore case, so `SQRT(2)` works, but user functions (below) don't.
+			// A user function can't be named after a builtin in any casing, see `BUILTINS`.
+			// Keep that list up to date when adding a builtin here.
+			match &*name.to_lowercase() {
 				"abs" => {
 					usage!(1);
 					use num::Signed;
@@ -280,116 +1050,796 @@ fn calc_level9<I: Iterator<Item = Token>>(context: &mut Context<I>, name: Option
 				},
 				"pow" => {
 					usage!(2);
+					context.spend_on(&args[1])?;
+					// 0 to any power is either 0, 1 or an error, and has no digits to estimate
 					use num::Zero;
-					args[0] = pow(mem::replace(&mut args[0], BigDecimal::zero()), args.remove(1), None)?;
+					if !args[0].is_zero() {
+						context.check_digits((log10(&args[0]) * to_f64(&args[1])).floor() + 1.0)?;
+					}
+					// Whole powers stay exact, only fractional ones need a logarithm
+					if args[1].with_scale(0) != args[1] {
+						context.warn(CalcWarning::Approximated(String::from("pow")));
+						let power = args.remove(1);
+						return powf(args.remove(0), power, context.div_scale);
+					}
+					if context.pow_cache_size == 0 {
+						return pow(args.remove(0), args.remove(0), None);
+					}
+					let key = (args.remove(0), args.remove(0));
+					if let Some(result) = context.pow_cache.get(&key) {
+						return Ok(result.clone());
+					}
+					let result = pow(key.0.clone(), key.1.clone(), None)?;
+					if context.pow_cache.len() >= context.pow_cache_size {
+						context.pow_cache.clear();
+					}
+					context.pow_cache.insert(key, result.clone());
+					return Ok(result);
 				},
-				_ => {
-					let tokens = match context.functions.get(&name) {
-						Some(tokens) => tokens.clone(),
-						None => return Err(CalcError::UnknownFunction(name))
-					};
-					let len = args.len();
-					for (i, arg) in args.into_iter().enumerate() {
-						let mut name = String::with_capacity(2);
-						name.push('$');
-						name.push_str(&(i + 1).to_string());
-						context.variables.insert(name, arg);
+				"fib" => {
+					usage!(1);
+					context.spend_on(&args[0])?;
+					return fib(args.remove(0));
+				},
+				"isqrt" => {
+					usage!(1);
+					return isqrt(args.remove(0), context.sqrt_max_iterations);
+				},
+				"sqrt" => {
+					let scale = scale!(1);
+					let num = args.remove(0);
+					let root = sqrt(num.clone(), scale, context.sqrt_max_iterations)?;
+					if context.warnings.is_some() && &root * &root != num {
+						context.warn(CalcWarning::Truncated(scale));
 					}
-					let val = calculate(&mut Context {
-						tokens: tokens.into_iter().peekable(),
-						level: context.level + 1,
-						variables: &mut context.variables,
-						functions: &mut context.functions
+					return Ok(root);
+				},
+				"hypot" => {
+					let scale = scale!(2);
+					let b = args.remove(1);
+					let a = args.remove(0);
+					let num = &a * &a + &b * &b;
+					let root = sqrt(num.clone(), scale, context.sqrt_max_iterations)?;
+					if context.warnings.is_some() && &root * &root != num {
+						context.warn(CalcWarning::Truncated(scale));
+					}
+					return Ok(root);
+				},
+				"dist" => {
+					usage!(2);
+					use num::Signed;
+					let b = args.remove(1);
+					return Ok((args.remove(0) - b).abs());
+				},
+				// 1 if `lo <= x <= hi`, otherwise 0. Nothing is between the bounds if `lo > hi`.
+				"between" => {
+					usage!(3);
+					let between = args[1] <= args[0] && args[0] <= args[2];
+					return Ok(BigDecimal::from(between as u8));
+				},
+				// `in_range(x, lo, hi, lo_incl, hi_incl)` is 1 if `x` is between `lo` and `hi`, otherwise 0.
+				// Each bound only counts as in the range if its flag isn't 0, so `in_range(x, 0, 1, 1, 0)` is `0 <= x < 1`.
+				"in_range" => {
+					usage!(5);
+					use num::Zero;
+					let above = if args[3].is_zero() { args[1] < args[0] } else { args[1] <= args[0] };
+					let below = if args[4].is_zero() { args[0] < args[2] } else { args[0] <= args[2] };
+					return Ok(BigDecimal::from((above && below) as u8));
+				},
+				// The size of the first argument with the sign of the second, where a sign of 0 counts as positive
+				"copysign" => {
+					usage!(2);
+					use num::Signed;
+					let sign = args.remove(1);
+					let magnitude = args.remove(0).abs();
+					return Ok(match sign.sign() {
+						Sign::NoSign |
+						Sign::Plus => magnitude,
+						Sign::Minus => -magnitude
 					});
-					for i in 1..len+1 {
-						let mut name = String::with_capacity(2);
-						name.push('$');
-						name.push_str(&i.to_string());
-						context.variables.remove(&name);
+				},
+				// The part after the decimal point, with the sign of the number: `frac(-3.25)` is -0.25
+				"frac" => {
+					usage!(1);
+					let num = args.remove(0);
+					let whole = num.with_scale(0);
+					return Ok(num - whole);
+				},
+				"sq" => {
+					usage!(1);
+					let num = args.remove(0);
+					return Ok(&num * &num);
+				},
+				"recip" => {
+					usage!(1);
+					let num = args.remove(0);
+
+					use num::{One, Zero};
+					if num.is_zero() {
+						return Err(CalcError::DivideByZero);
 					}
-					return val;
-				}
-			}
-		} else {
-			usage!(1);
-		}
 
-		if args.is_empty() {
-			use num::Zero;
-			return Ok(BigDecimal::zero())
-		} else {
-			return Ok(args.remove(0));
-		}
-	} else if name.is_none() {
-		if let Some(&Token::BlockName(_)) = context.tokens.peek() {
-			// Really ugly code, but we need to know the type *before* we walk out on it
-			if let Some(Token::BlockName(name)) = context.tokens.next() {
-				return calc_level9(context, Some(name));
-			}
-		}
-	}
+					return Ok(div_round(&BigDecimal::one(), &num, context.div_scale, context.round_mode));
+				},
+				// How many percent `new` is more than `old`, or negative if less: `pct_change(80, 100)` is 25
+				"pct_change" => {
+					usage!(2);
+					let new = args.remove(1);
+					let old = args.remove(0);
 
-	Ok(get_number(context)?)
-}
-fn get_number<I: Iterator<Item = Token>>(context: &mut Context<I>) -> Result<BigDecimal, CalcError> {
-	match context.tokens.next() {
-		Some(Token::Num(num)) => Ok(num),
-		Some(Token::Sub) => {
-			Ok(-calc_level9(context, None)?)
-		},
-		Some(Token::VarAssign(name)) => {
-			if let Some(&Token::ParenOpen) = context.tokens.peek() {
-				context.tokens.next();
-				let mut fn_tokens = Vec::new();
-
-				let mut depth = 1;
-				loop {
-					let token = match context.tokens.next() {
-						Some(Token::Separator) if depth == 1 => return Err(CalcError::SeparatorInDef),
-						Some(token) => token,
-						None => return Err(CalcError::UnclosedParen)
-					};
-					if token == Token::ParenOpen {
-						depth += 1;
-					} else if token == Token::ParenClose {
-						depth -= 1;
+					use num::Zero;
+					if old.is_zero() {
+						return Err(CalcError::DivideByZero);
 					}
-					fn_tokens.push(token);
 
-					if depth == 0 {
-						break;
-					} else if depth == std::u8::MAX {
-						return Err(CalcError::TooDeep);
+					// Multiplying first keeps it exact for as long as the division is
+					let change = (new - &old) * BigDecimal::from(100);
+					let result = div_round(&change, &old, context.div_scale, context.round_mode);
+					if context.warnings.is_some() && &result * &old != change {
+						context.warn(CalcWarning::Truncated(context.div_scale));
 					}
-				}
-
-				context.functions.insert(name, fn_tokens);
-			} else {
-				let val = calculate(context)?;
-				context.variables.insert(name, val);
-			}
-			use num::Zero;
-			Ok(BigDecimal::zero())
-		},
-		Some(Token::VarGet(name)) => {
-			Ok(
-				match context.variables.get(&name) {
-					Some(val) => val.clone(),
-					None => return Err(CalcError::UnknownVariable(name))
-				}
-			)
-		},
-		_ => Err(CalcError::InvalidSyntax)
-	}
-}
-fn require_whole(num: &BigDecimal) -> Result<(), CalcError> {
-	if num.with_scale(0) == *num {
-		Ok(())
-	} else {
-		Err(CalcError::NotAWhole)
+					return Ok(result);
+				},
+				// `ans(1)` is the last result in `Context::history`, `ans(2)` the one before that, ...
+				"ans" => {
+					usage!(1);
+					let index = args.remove(0);
+					require_positive(&index)?;
+					use num::ToPrimitive;
+					let index = to_primitive!(index, to_usize, "usize", "ans");
+					let result = match context.history {
+						Some(ref history) if index >= 1 => history.get(index - 1).cloned(),
+						_ => None
+					};
+					return result.ok_or(CalcError::OutOfDomain("from 1 up to the number of remembered results"));
+				},
+				// Rounds to a whole number, or to the given number of decimals like `round(2.345, 2)`
+				"round" => {
+					let scale = if args.len() == 2 {
+						let scale = args.pop().unwrap();
+						require_positive(&scale)?;
+						use num::ToPrimitive;
+						to_primitive!(scale, to_u64, "u64", "round")
+					} else {
+						usage!(1);
+						0
+					};
+					return Ok(round(&args.remove(0), scale, context.round_mode));
+				},
+				"exp" => {
+					let scale = scale!(1);
+					context.spend_on(&args[0])?;
+					return exp(args.remove(0), scale);
+				},
+				"ln" => {
+					let scale = scale!(1);
+					return ln(args.remove(0), scale);
+				},
+				"sinh" => {
+					let scale = scale!(1);
+					context.spend_on(&args[0])?;
+					return sinh(args.remove(0), scale);
+				},
+				"cosh" => {
+					let scale = scale!(1);
+					context.spend_on(&args[0])?;
+					return cosh(args.remove(0), scale);
+				},
+				"tanh" => {
+					let scale = scale!(1);
+					return tanh(args.remove(0), scale);
+				},
+				"sin" => {
+					let scale = scale!(1);
+					return Ok(sin(args.remove(0), scale));
+				},
+				"cos" => {
+					let scale = scale!(1);
+					return Ok(cos(args.remove(0), scale));
+				},
+				"tan" => {
+					let scale = scale!(1);
+					return tan(args.remove(0), scale);
+				},
+				"normalize_angle" => {
+					let scale = scale!(1);
+					return Ok(normalize_angle(args.remove(0), scale));
+				},
+				"todeg" => {
+					let scale = scale!(1);
+					return Ok(todeg(args.remove(0), scale));
+				},
+				"torad" => {
+					let scale = scale!(1);
+					return Ok(torad(args.remove(0), scale));
+				},
+				"asin" => {
+					let scale = scale!(1);
+					return asin(args.remove(0), scale);
+				},
+				"acos" => {
+					let scale = scale!(1);
+					return acos(args.remove(0), scale);
+				},
+				"atan" => {
+					let scale = scale!(1);
+					return Ok(atan(args.remove(0), scale));
+				},
+				"atan2" => {
+					let scale = scale!(2);
+					let x = args.remove(1);
+					return Ok(atan2(args.remove(0), x, scale));
+				},
+				"dfact" => {
+					usage!(1);
+					context.spend_on(&args[0])?;
+					// n!! * (n - 1)!! is n!, so n!! has about half its digits
+					context.check_digits(factorial_digits(&args[0]) / 2.0)?;
+					return double_factorial(args.remove(0));
+				},
+				"gamma" => {
+					let scale = scale!(1);
+					context.spend_on(&args[0])?;
+					context.check_digits(factorial_digits(&(&args[0] - BigDecimal::from(1))))?;
+					// Only whole numbers use the exact factorial
+					if args[0].with_scale(0) != args[0] {
+						context.warn(CalcWarning::Approximated(String::from("gamma")));
+					}
+					return gamma(args.remove(0), scale);
+				},
+				"is_prime" => {
+					usage!(1);
+					return is_prime(args.remove(0));
+				},
+				"omega" => {
+					usage!(1);
+					return omega(args.remove(0));
+				},
+				"bigomega" => {
+					usage!(1);
+					return bigomega(args.remove(0));
+				},
+				"powmod" => {
+					usage!(3);
+					let modulus = args.remove(2);
+					let exp = args.remove(1);
+					return powmod(args.remove(0), exp, modulus);
+				},
+				"invmod" => {
+					usage!(2);
+					let modulus = args.remove(1);
+					return invmod(args.remove(0), modulus);
+				},
+				"mod" => {
+					usage!(2);
+					let divisor = args.remove(1);
+					return modulo(args.remove(0), divisor);
+				},
+				"popcount" => {
+					usage!(1);
+					return popcount(args.remove(0));
+				},
+				"bitlen" => {
+					usage!(1);
+					return bitlen(args.remove(0));
+				},
+				"rotl" => {
+					usage!(3);
+					let width = args.remove(2);
+					if width > BigDecimal::from(context.max_shift as i64) {
+						return Err(CalcError::ShiftTooLarge);
+					}
+					let bits = args.remove(1);
+					return rotl(args.remove(0), bits, width);
+				},
+				"rotr" => {
+					usage!(3);
+					let width = args.remove(2);
+					if width > BigDecimal::from(context.max_shift as i64) {
+						return Err(CalcError::ShiftTooLarge);
+					}
+					let bits = args.remove(1);
+					return rotr(args.remove(0), bits, width);
+				},
+				"digitsum" => {
+					usage!(1);
+					return digitsum(args.remove(0));
+				},
+				"digroot" => {
+					usage!(1);
+					return digroot(args.remove(0));
+				},
+				// `x == mantissa(x) * pow(10, exponent10(x))`
+				"mantissa" => {
+					usage!(1);
+					return Ok(BigDecimal::new(decompose(&args[0]).0, 0));
+				},
+				"exponent10" => {
+					usage!(1);
+					return Ok(BigDecimal::from(decompose(&args[0]).1));
+				},
+				"ilog" => {
+					usage!(2);
+					let num = args.remove(1);
+					return ilog(args.remove(0), num);
+				},
+				"reverse" => {
+					usage!(1);
+					return reverse(args.remove(0));
+				},
+				// Variadic like `sum`, but there is nothing sensible for `max()` to be, so that's an error
+				function @ "min" | function @ "max" => {
+					if args.is_empty() {
+						return Err(CalcError::IncorrectArguments(1, 0));
+					}
+					context.spend(args.len() as u64)?;
+					let args = args.into_iter();
+					return Ok(if function == "min" { args.min() } else { args.max() }.unwrap());
+				},
+				"rand" => {
+					if args.len() == 2 {
+						let below = args.remove(1);
+						return rand_below(args.remove(0), below);
+					}
+					usage!(1);
+					return rand(args.remove(0));
+				},
+				// Folded left to right, like `gcd(gcd(a, b), c)`
+				function @ "gcd" | function @ "lcm" => {
+					if args.len() < 2 {
+						return Err(CalcError::IncorrectArguments(2, args.len()));
+					}
+					context.spend(args.len() as u64)?;
+
+					let mut args = args.into_iter();
+					let mut result = args.next().unwrap();
+					for arg in args {
+						result = if function == "gcd" { gcd(result, arg)? } else { lcm(result, arg)? };
+					}
+					return Ok(result);
+				},
+				"avg" | "mean" => {
+					if args.len() < 2 {
+						return Err(CalcError::IncorrectArguments(2, args.len()));
+					}
+					context.spend(args.len() as u64)?;
+
+					let count = BigDecimal::from(args.len() as i64);
+					use num::Zero;
+					if count.is_zero() {
+						return Err(CalcError::DivideByZero);
+					}
+
+					let sum = args.into_iter().fold(BigDecimal::zero(), |acc, arg| acc + arg);
+					return Ok(div_round(&sum, &count, context.div_scale, context.round_mode));
+				},
+				_ => {
+					// Looked up now rather than when the body containing this call was defined,
+					// so definitions only need to exist by the time they're called
+					let tokens = match context.functions.get(&name) {
+						Some(tokens) => tokens.clone(),
+						None => return Err(CalcError::UnknownFunction(name, context.locate(pos)))
+					};
+					let call = (name, args.clone());
+					if context.memoize {
+						if let Some(val) = context.cache.get(&call) {
+							return Ok(val.clone());
+						}
+					}
+					// Calling a function again with the same arguments, before the first call is done
+					// and without any variable changing in between, would do exactly the same forever.
+					// When something did change, like a counter, it might be what ends the recursion.
+					let repeated = context.calls.iter()
+						.any(|&(ref earlier, changes)| *earlier == call && changes == context.changes);
+					if repeated {
+						return Err(CalcError::InfiniteRecursion(call.0));
+					}
+					// Everything the body assigns is undone afterwards, including `const`s
+					let outer = if context.isolate_functions {
+						Some((context.variables.clone(), context.protected.clone()))
+					} else {
+						None
+					};
+					let len = args.len();
+					for (i, arg) in args.into_iter().enumerate() {
+						let mut name = String::with_capacity(2);
+						name.push(context.param_prefix);
+						name.push_str(&(i + 1).to_string());
+						if context.variables.get(&name) != Some(&arg) {
+							context.changes += 1;
+						}
+						context.variables.insert(name, arg);
+					}
+					context.calls.push((call, context.changes));
+					let positions = vec![pos; tokens.len() + 1];
+					let val = calculate_nested(context, tokens, positions);
+					let (call, _) = context.calls.pop().unwrap();
+					for i in 1..len+1 {
+						let mut name = String::with_capacity(2);
+						name.push(context.param_prefix);
+						name.push_str(&i.to_string());
+						context.variables.remove(&name);
+					}
+					if let Some((variables, protected)) = outer {
+						*context.variables = variables;
+						context.protected = protected;
+					}
+					context.changes += 1;
+					if context.memoize {
+						if let Ok(ref val) = val {
+							context.cache.insert(call, val.clone());
+						}
+					}
+					return val;
+				}
+			}
+		} else {
+			usage!(1);
+		}
+
+		// Builtins that get here (like `abs`) have checked their arguments and left the result in `args[0]`,
+		// so this shouldn't happen. Builtins that take no arguments must return their own result or error.
+		if args.is_empty() {
+			use num::Zero;
+			return Ok(BigDecimal::zero())
+		} else {
+			return Ok(args.remove(0));
+		}
+	} else if name.is_none() {
+		if let Some(&Token::BlockName(_)) = context.tokens.peek() {
+			// Really ugly code, but we need to know the type *before* we walk out on it
+			if let Some(Token::BlockName(name)) = context.next() {
+				return calc_level9(context, Some(name));
+			}
+		} else if let Some(&Token::Bar) = context.tokens.peek() {
+			context.next();
+
+			context.level += 1;
+			let expr = calculate(context)?;
+			context.level -= 1;
+
+			if Some(Token::Bar) != context.next() {
+				return Err(CalcError::UnclosedBar);
+			}
+
+			use num::Signed;
+			return Ok(expr.abs());
+		}
+	}
+
+	Ok(get_number(context)?)
+}
+/// Calculates `sum_range` or `product_range`, right after the opening parenthesis.
+/// Like `sum_range(i, 1, 5, i*i)`, the last argument is calculated for every whole number
+/// from the second to the third with the name set to it, and the results are summed up (or multiplied).
+/// That's why the arguments are read as tokens first, since the last one is calculated more than once.
+/// These have their own names, since `sum` and `product` are variadic, see `BUILTINS`.
+fn calc_aggregate<I: Iterator<Item = Token>>(context: &mut Context<I>, product: bool) -> CalcResult<BigDecimal> {
+	use num::{One, Zero};
+	let combine = |acc: BigDecimal, val: BigDecimal| if product { acc * val } else { acc + val };
+	let identity = if product { BigDecimal::one() } else { BigDecimal::zero() };
+
+	let mut args = read_args(context)?;
+	if args.len() != 4 {
+		return Err(CalcError::IncorrectArguments(4, args.len()));
+	}
+	let var = match args[0].0[..] {
+		[Token::VarGet(ref var)] => var.clone(),
+		_ => return Err(CalcError::InvalidSyntax(context.locate(args[0].1.first().cloned().unwrap_or(0))))
+	};
+
+	if context.protected.contains(&var) {
+		return Err(CalcError::ReadOnly(var));
+	}
+	let (body, body_positions) = args.pop().unwrap();
+	let (to, to_positions) = args.pop().unwrap();
+	let (from, from_positions) = args.pop().unwrap();
+	let mut i = calculate_nested(context, from, from_positions)?;
+	let to = calculate_nested(context, to, to_positions)?;
+	require_whole(&i)?;
+	require_whole(&to)?;
+	if to >= i {
+		context.spend_on(&(&to - &i + BigDecimal::one()))?;
+	}
+
+	// The name might already be a variable, which comes back once the loop is done
+	let outer = context.variables.remove(&var);
+	let mut result = Ok(identity);
+	while i <= to {
+		context.variables.insert(var.clone(), i.clone());
+		context.changes += 1;
+		result = match calculate_nested(context, body.clone(), body_positions.clone()) {
+			Ok(val) => result.map(|acc| combine(acc, val)),
+			Err(err) => Err(err)
+		};
+		if result.is_err() {
+			break;
+		}
+		i += BigDecimal::one();
+	}
+	match outer {
+		Some(outer) => context.variables.insert(var, outer),
+		None => context.variables.remove(&var)
+	};
+	context.changes += 1;
+	result
+}
+/// Calculates `sum` or `product`, right after the opening parenthesis, which add up (or multiply) all their arguments.
+/// They're variadic, so `sum()` and `product()` are just the empty sum and product.
+/// A name first that the last of four arguments uses, like `sum(i, 1, 5, i*i)`, is meant as a sum over a range,
+/// so it fails with `RangeInSum` pointing at `sum_range`. Other calls, like `sum(x, 1, 2, 3)`, just add up.
+fn calc_variadic<I: Iterator<Item = Token>>(context: &mut Context<I>, name: String) -> CalcResult<BigDecimal> {
+	use num::{One, Zero};
+	let args = read_args(context)?;
+	if args.len() == 4 {
+		if let [Token::VarGet(ref var)] = args[0].0[..] {
+			if args[3].0.iter().any(|token| matches!(*token, Token::VarGet(ref name) if name == var)) {
+				return Err(CalcError::RangeInSum(name));
+			}
+		}
+	}
+
+	context.spend(args.len() as u64)?;
+	let product = name == "product";
+	let mut result = if product { BigDecimal::one() } else { BigDecimal::zero() };
+	for (tokens, positions) in args {
+		let val = calculate_nested(context, tokens, positions)?;
+		result = if product { result * val } else { result + val };
+	}
+	Ok(result)
+}
+/// Reads the arguments of a call as tokens, right after the opening parenthesis and up to and including the closing one.
+/// Each argument comes with positions like `Context::positions`, ending at the `,` or `)` after it.
+fn read_args<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Vec<(Vec<Token>, Vec<usize>)>> {
+	let mut args = Vec::new();
+	if let Some(&Token::ParenClose) = context.tokens.peek() {
+		context.next();
+		return Ok(args);
+	}
+
+	let mut tokens = Vec::new();
+	let mut positions = Vec::new();
+	let mut depth = 0;
+	loop {
+		let token = match context.next() {
+			Some(token) => token,
+			None => return Err(CalcError::UnclosedParen)
+		};
+		let pos = context.position();
+		match token {
+			Token::ParenOpen => depth += 1,
+			Token::ParenClose |
+			Token::Separator if depth == 0 => {
+				positions.push(pos);
+				args.push((mem::take(&mut tokens), mem::take(&mut positions)));
+				if token == Token::ParenClose {
+					return Ok(args);
+				}
+				continue;
+			},
+			Token::ParenClose => depth -= 1,
+			_ => {}
+		}
+		tokens.push(token);
+		positions.push(pos);
+	}
+}
+/// Calculates `tokens` (like a function body) one level deeper,
+/// sharing variables, functions and limits with `context`.
+/// Errors inside point at `positions`, which work like `Context::positions`.
+/// Tokens that don't come from the input, like a function body, can all point at the call.
+fn calculate_nested<I: Iterator<Item = Token>>(context: &mut Context<I>, tokens: Vec<Token>, positions: Vec<usize>)
+		-> CalcResult<BigDecimal> {
+	let mut nested = Context {
+		level: context.level + 1,
+		consumed: 0,
+		cache: mem::take(&mut context.cache),
+		pow_cache: mem::take(&mut context.pow_cache),
+		calls: mem::take(&mut context.calls),
+		changes: context.changes,
+		positions,
+		line_starts: mem::take(&mut context.line_starts),
+		tokens: tokens.into_iter().peekable(),
+		variables: &mut *context.variables,
+		functions: &mut *context.functions,
+		op_budget: context.op_budget,
+		protected: mem::take(&mut context.protected),
+		read_only: context.read_only,
+		max_shift: context.max_shift,
+		max_digits: context.max_digits,
+		sqrt_max_iterations: context.sqrt_max_iterations,
+		div_scale: context.div_scale,
+		epsilon: context.epsilon.clone(),
+		round_mode: context.round_mode,
+		memoize: context.memoize,
+		isolate_functions: context.isolate_functions,
+		pow_cache_size: context.pow_cache_size,
+		param_prefix: context.param_prefix,
+		trace: context.trace.as_deref_mut(),
+		history: context.history.as_deref_mut(),
+		history_size: context.history_size,
+		last_error: None,
+		warnings: context.warnings.take()
+	};
+	let val = calculate(&mut nested);
+	context.op_budget = nested.op_budget;
+	context.cache = nested.cache;
+	context.pow_cache = nested.pow_cache;
+	context.calls = nested.calls;
+	context.line_starts = nested.line_starts;
+	context.changes = nested.changes;
+	context.protected = nested.protected;
+	context.warnings = nested.warnings;
+	val
+}
+fn get_number<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<BigDecimal> {
+	match context.next() {
+		Some(Token::Num(num)) => Ok(num),
+		Some(Token::Add) => {
+			calc_level9(context, None)
+		},
+		Some(Token::Sub) => {
+			Ok(-calc_level9(context, None)?)
+		},
+		Some(Token::VarAssign(_)) |
+		Some(Token::Const) if context.read_only => Err(CalcError::AssignmentNotAllowed),
+		Some(Token::VarAssign(name)) => {
+			if let Some(&Token::ParenOpen) = context.tokens.peek() {
+				context.next();
+				let pos = context.position();
+				let fn_tokens = read_function_body(context)?;
+
+				// Only a parenthesis that's the whole value is a function body,
+				// so `y = (x = 3) + 1` assigns 4 to `y` instead
+				if !ends_expression(context) {
+					if context.protected.contains(&name) {
+						return Err(CalcError::ReadOnly(name));
+					}
+					let mut tokens = vec![Token::ParenOpen];
+					tokens.extend(fn_tokens);
+					let mut positions = vec![pos; tokens.len()];
+					let (rest, rest_positions) = read_rest(context);
+					tokens.extend(rest);
+					positions.extend(rest_positions);
+
+					let val = calculate_nested(context, tokens, positions)?;
+					context.variables.insert(name, val.clone());
+					context.changes += 1;
+					return Ok(val);
+				}
+
+				if BUILTINS.contains(&&*name.to_lowercase()) {
+					return Err(CalcError::ReservedName(name));
+				}
+				// Other functions might call this one, so none of the remembered results can be trusted
+				context.cache.clear();
+				context.functions.insert(name, fn_tokens);
+				context.changes += 1;
+
+				// A function has no value
+				use num::Zero;
+				Ok(BigDecimal::zero())
+			} else {
+				if context.protected.contains(&name) {
+					return Err(CalcError::ReadOnly(name));
+				}
+				let val = calculate(context)?;
+				context.variables.insert(name, val.clone());
+				context.changes += 1;
+				Ok(val)
+			}
+		},
+		// `const k = 5` calculates `5` right away and makes `k` read-only, see `Context::define_constant`
+		Some(Token::Const) => {
+			match context.next() {
+				Some(Token::VarAssign(name)) => {
+					if context.protected.contains(&name) {
+						return Err(CalcError::ReadOnly(name));
+					}
+					let val = calculate(context)?;
+					context.define_constant(&name, val.clone());
+					Ok(val)
+				},
+				_ => Err(CalcError::InvalidSyntax(context.location()))
+			}
+		},
+		Some(Token::VarGet(name)) => {
+			Ok(
+				match context.lookup(&name) {
+					Some(val) => val,
+					None => return Err(CalcError::UnknownVariable(name, context.location()))
+				}
+			)
+		},
+		_ => Err(CalcError::InvalidSyntax(context.location()))
+	}
+}
+/// Reads the tokens of a function definition up to and including its closing parenthesis.
+/// The opening parenthesis must already be consumed.
+pub(crate) fn read_function_body<I: Iterator<Item = Token>>(context: &mut Context<I>) -> CalcResult<Vec<Token>> {
+	let mut fn_tokens = Vec::new();
+
+	let mut depth = 1;
+	loop {
+		let token = match context.next() {
+			Some(Token::Separator) if depth == 1 => return Err(CalcError::SeparatorInDef),
+			Some(token) => token,
+			None => return Err(CalcError::UnclosedParen)
+		};
+		if token == Token::ParenOpen {
+			depth += 1;
+		} else if token == Token::ParenClose {
+			depth -= 1;
+		}
+		fn_tokens.push(token);
+
+		if depth == 0 {
+			break;
+		} else if depth == u8::MAX {
+			return Err(CalcError::TooDeep);
+		}
+	}
+
+	Ok(fn_tokens)
+}
+/// Returns true if the next token ends the expression being calculated,
+/// like a `;` or the `)` of the parenthesis it's in
+pub(crate) fn ends_expression<I: Iterator<Item = Token>>(context: &mut Context<I>) -> bool {
+	match context.tokens.peek() {
+		None |
+		Some(&Token::Semicolon) => true,
+		Some(&Token::ParenClose) |
+		Some(&Token::Separator) |
+		Some(&Token::Bar) => context.level != 0,
+		_ => false
+	}
+}
+/// Reads the tokens up to the end of the expression being calculated, like the `+ 1` in `y = (x = 3) + 1`.
+/// What ends it is left for the caller: a `;`, or a `)`, `,` or `|` that belongs to a surrounding parenthesis or bar.
+/// Each token comes with its position, followed by the position of what ended it, like `Context::positions`.
+pub(crate) fn read_rest<I: Iterator<Item = Token>>(context: &mut Context<I>) -> (Vec<Token>, Vec<usize>) {
+	let mut tokens = Vec::new();
+	let mut positions = Vec::new();
+	let mut depth = 0;
+	// Bars opened in the rest itself, and whether the last token was one that closed
+	let mut bars = 0;
+	let mut bar_closed = false;
+	loop {
+		let after_operand = bar_closed || matches!(tokens.last(),
+			Some(&Token::Num(_)) | Some(&Token::VarGet(_)) | Some(&Token::ParenClose) | Some(&Token::Factorial));
+		bar_closed = false;
+		match context.tokens.peek() {
+			None |
+			Some(&Token::Semicolon) if depth == 0 => break,
+			Some(&Token::ParenClose) |
+			Some(&Token::Separator) if depth == 0 => break,
+			Some(&Token::Bar) if depth == 0 && after_operand => {
+				if bars == 0 {
+					break;
+				}
+				bars -= 1;
+				bar_closed = true;
+			},
+			Some(&Token::Bar) if depth == 0 => bars += 1,
+			Some(&Token::ParenOpen) => depth += 1,
+			Some(&Token::ParenClose) => depth -= 1,
+			None => break,
+			_ => {}
+		}
+		tokens.push(context.next().unwrap());
+		positions.push(context.position());
+	}
+	positions.push(context.position());
+	(tokens, positions)
+}
+fn require_whole(num: &BigDecimal) -> CalcResult<()> {
+	if num.with_scale(0) == *num {
+		Ok(())
+	} else {
+		Err(CalcError::NotAWhole)
 	}
 }
-fn require_positive(num: &BigDecimal) -> Result<(), CalcError> {
+fn require_positive(num: &BigDecimal) -> CalcResult<()> {
 	match num.sign() {
 		Sign::NoSign |
 		Sign::Plus => Ok(()),
@@ -397,8 +1847,10 @@ fn require_positive(num: &BigDecimal) -> Result<(), CalcError> {
 	}
 }
 /// Calculates the factorial of `num`
-pub fn factorial(num: BigDecimal, result: Option<BigDecimal>) -> Result<BigDecimal, CalcError> {
-	require_whole(&num)?;
+pub fn factorial(num: BigDecimal, result: Option<BigDecimal>) -> CalcResult<BigDecimal> {
+	if require_whole(&num).is_err() {
+		return Err(CalcError::FractionalFactorial);
+	}
 	require_positive(&num)?;
 
 	use num::{Zero, One};
@@ -411,12 +1863,60 @@ pub fn factorial(num: BigDecimal, result: Option<BigDecimal>) -> Result<BigDecim
 		factorial(num - BigDecimal::one(), result)
 	}
 }
-/// Calculates `num` to the power of `power`
-pub fn pow(num: BigDecimal, power: BigDecimal, result: Option<BigDecimal>) -> Result<BigDecimal, CalcError> {
+/// Calculates the double factorial of `num`: the product of every whole number
+/// from `num` down to 1 that has the same parity, so `dfact(7)` is `7 * 5 * 3 * 1`
+pub fn double_factorial(num: BigDecimal) -> CalcResult<BigDecimal> {
+	if require_whole(&num).is_err() {
+		return Err(CalcError::FractionalFactorial);
+	}
 	require_positive(&num)?;
+
+	use num::{One, Zero};
+	use num::bigint::ToBigInt;
+	let two = BigInt::from(2);
+	let mut num = num.to_bigint().unwrap();
+	let mut result = BigInt::one();
+	while num > BigInt::zero() {
+		result = result * &num;
+		num = num - &two;
+	}
+	Ok(BigDecimal::new(result, 0))
+}
+/// Estimates how many digits `num!` has before the decimal point, using Stirling's approximation
+fn factorial_digits(num: &BigDecimal) -> f64 {
+	let num = to_f64(num);
+	if num < 2.0 {
+		return 1.0;
+	}
+	// ln(n!) is about n ln(n) - n + ln(2 pi n) / 2
+	let ln = num * num.ln() - num + (2.0 * std::f64::consts::PI * num).ln() / 2.0;
+	(ln / std::f64::consts::LN_10).floor() + 1.0
+}
+/// Estimates the base 10 logarithm of the absolute value of `num`, even when it's too large for an `f64`
+fn log10(num: &BigDecimal) -> f64 {
+	use num::Signed;
+	let (digits, scale) = num.as_bigint_and_exponent();
+	let digits = digits.abs().to_str_radix(10);
+	let leading = digits.len().min(15);
+	let leading_value: f64 = digits[..leading].parse().unwrap();
+	leading_value.log10() + (digits.len() - leading) as f64 - scale as f64
+}
+/// Converts to an `f64` for estimating, with numbers too large for one becoming infinity
+fn to_f64(num: &BigDecimal) -> f64 {
+	use num::Signed;
+	result_to_f64(num).unwrap_or(if num.is_negative() { f64::NEG_INFINITY } else { f64::INFINITY })
+}
+/// Calculates `num` to the power of `power`.
+/// `power` has to be whole, which is also why negative bases are fine:
+/// the sign of the result only depends on whether `power` is even or odd.
+/// `pow(0, 0)` is 1, like it usually is for whole powers, and 0 to a negative power fails with `DivideByZero`.
+pub fn pow(num: BigDecimal, power: BigDecimal, result: Option<BigDecimal>) -> CalcResult<BigDecimal> {
 	require_whole(&power)?;
 
-	use num::{Zero, One};
+	use num::{Signed, Zero, One};
+	if num.is_zero() && power.is_negative() {
+		return Err(CalcError::DivideByZero);
+	}
 	let one = BigDecimal::one();
 	if power.is_zero() {
 		Ok(result.unwrap_or(one))
@@ -443,3 +1943,2018 @@ pub fn pow(num: BigDecimal, power: BigDecimal, result: Option<BigDecimal>) -> Re
 		}
 	}
 }
+/// Calculates `num` to the power of `power` as e^(power ln(num)), cut off after `scale` decimals.
+/// Unlike `pow`, `power` can be a fraction, but `num` has to be positive.
+pub fn powf(num: BigDecimal, power: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::{Signed, Zero};
+	if num.is_zero() && power.is_negative() {
+		return Err(CalcError::DivideByZero);
+	}
+	if !num.is_positive() {
+		return Err(CalcError::OutOfDomain("greater than 0"));
+	}
+
+	// Every digit before the decimal point of the result needs one more correct decimal in the exponent
+	let digits = (log10(&num) * to_f64(&power)).max(0.0).ceil() as u64;
+	let work = scale + digits + GUARD_DIGITS;
+	let exponent = (power * ln(num, work)?).with_scale(work as i64);
+	// Round instead of cutting off, so that `pow(9, 0.5)` is 3 and not 2.999...
+	let half = BigDecimal::new(BigInt::from(5), scale as i64 + 1);
+	Ok((exp(exponent, work)? + half).with_scale(scale as i64))
+}
+/// Divides `num` by `divisor` and rounds down, towards negative infinity.
+/// Unlike `/`, this is always exact.
+pub fn floor_div(num: &BigDecimal, divisor: &BigDecimal) -> BigDecimal {
+	use num::Integer;
+	use std::cmp;
+
+	// Scaling both up to the same scale turns them into integers with the same quotient
+	let scale = cmp::max(num.as_bigint_and_exponent().1, divisor.as_bigint_and_exponent().1);
+	let (num, _) = num.with_scale(scale).into_bigint_and_exponent();
+	let (divisor, _) = divisor.with_scale(scale).into_bigint_and_exponent();
+	BigDecimal::new(num.div_floor(&divisor), 0)
+}
+/// Returns a pseudo-random number from 0 up to (but not including) 1, with 18 decimals.
+/// The same `seed` always gives the same number, and nothing else affects it.
+pub fn rand(seed: BigDecimal) -> CalcResult<BigDecimal> {
+	let random = random_u64(&seed)?;
+	Ok(BigDecimal::new(BigInt::from(random % 1_000_000_000_000_000_000), 18))
+}
+/// Returns a pseudo-random whole number from 0 up to (but not including) `below`. See `rand`.
+pub fn rand_below(seed: BigDecimal, below: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&below)?;
+	use num::Signed;
+	if !below.is_positive() {
+		return Err(CalcError::OutOfDomain("greater than 0"));
+	}
+
+	let random = random_u64(&seed)?;
+	use num::bigint::ToBigInt;
+	Ok(BigDecimal::new(BigInt::from(random) % below.to_bigint().unwrap(), 0))
+}
+fn random_u64(seed: &BigDecimal) -> CalcResult<u64> {
+	require_whole(seed)?;
+
+	// Any whole number works as a seed, by wrapping it into 64 bits
+	use num::{Integer, ToPrimitive};
+	use num::bigint::ToBigInt;
+	let seed = seed.to_bigint().unwrap().mod_floor(&(BigInt::from(1) << 64)).to_u64().unwrap();
+
+	// SplitMix64
+	let mut random = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	random = (random ^ (random >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	random = (random ^ (random >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	Ok(random ^ (random >> 31))
+}
+/// Calculates the greatest common divisor of `a` and `b`, which is never negative
+pub fn gcd(a: BigDecimal, b: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&a)?;
+	require_whole(&b)?;
+
+	use num::Integer;
+	use num::bigint::ToBigInt;
+	Ok(BigDecimal::new(a.to_bigint().unwrap().gcd(&b.to_bigint().unwrap()), 0))
+}
+/// Calculates the least common multiple of `a` and `b`, which is never negative.
+/// If either is 0, so is the result.
+pub fn lcm(a: BigDecimal, b: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&a)?;
+	require_whole(&b)?;
+
+	use num::{Integer, Zero};
+	use num::bigint::ToBigInt;
+	if a.is_zero() || b.is_zero() {
+		return Ok(BigDecimal::zero());
+	}
+	Ok(BigDecimal::new(a.to_bigint().unwrap().lcm(&b.to_bigint().unwrap()), 0))
+}
+/// Divides `num` by `divisor`, cut off after `scale` decimals if the result doesn't end before that.
+/// `divisor` must not be zero.
+pub fn div(num: &BigDecimal, divisor: &BigDecimal, scale: u64) -> BigDecimal {
+	let quotient = num / divisor;
+	if quotient.as_bigint_and_exponent().1 <= scale as i64 && &quotient * divisor == *num {
+		return quotient;
+	}
+
+	// num / divisor = (int1 / int2) * 10^(scale2 - scale1),
+	// so shifting the ints by that and by `scale` gives the quotient as an int with `scale` decimals
+	let (mut int1, scale1) = num.as_bigint_and_exponent();
+	let (mut int2, scale2) = divisor.as_bigint_and_exponent();
+	let shift = scale as i64 + scale2 - scale1;
+
+	use num::pow;
+	if shift >= 0 {
+		int1 = int1 * pow(BigInt::from(10), shift as usize);
+	} else {
+		int2 = int2 * pow(BigInt::from(10), -shift as usize);
+	}
+	BigDecimal::new(int1 / int2, scale as i64)
+}
+/// Same as `div`, but rounds the last decimal with `mode` instead of cutting off
+pub fn div_round(num: &BigDecimal, divisor: &BigDecimal, scale: u64, mode: RoundMode) -> BigDecimal {
+	use num::{Signed, Zero};
+	let quotient = div(num, divisor, scale);
+	let rest = num - &quotient * divisor;
+	if rest.is_zero() {
+		return quotient;
+	}
+
+	// `quotient` is cut off towards zero, and rest / divisor is how much of the last decimal was cut off
+	let last = BigDecimal::new(BigInt::from(1), scale as i64);
+	let half = (rest.abs() * BigDecimal::from(2)).cmp(&(divisor.abs() * &last));
+	let away = match half {
+		std::cmp::Ordering::Less => false,
+		std::cmp::Ordering::Greater => true,
+		std::cmp::Ordering::Equal => match mode {
+			RoundMode::HalfUp => true,
+			RoundMode::HalfEven => {
+				use num::Integer;
+				quotient.with_scale(scale as i64).into_bigint_and_exponent().0.is_odd()
+			}
+		}
+	};
+	if !away {
+		quotient.with_scale(scale as i64)
+	} else if rest.is_negative() == divisor.is_negative() {
+		quotient + last
+	} else {
+		quotient - last
+	}
+}
+/// Rounds `num` to `scale` decimals with `mode`. Numbers with fewer decimals stay the same.
+pub fn round(num: &BigDecimal, scale: u64, mode: RoundMode) -> BigDecimal {
+	use num::One;
+	div_round(num, &BigDecimal::one(), scale, mode)
+}
+/// Calculates the Euclidean remainder of `num` divided by `divisor`.
+/// The result is never negative, no matter the signs of the inputs: `mod(-1, 3)` is 2,
+/// where a remainder from truncating division (like `%` in most languages) would be -1.
+pub fn modulo(num: BigDecimal, divisor: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_whole(&divisor)?;
+
+	use num::{Integer, Signed, Zero};
+	use num::bigint::ToBigInt;
+	if divisor.is_zero() {
+		return Err(CalcError::DivideByZero);
+	}
+	let divisor = divisor.to_bigint().unwrap().abs();
+	Ok(BigDecimal::new(num.to_bigint().unwrap().mod_floor(&divisor), 0))
+}
+/// Calculates the `num`th Fibonacci number.
+/// This is a loop and not a recursion, since the numbers get big quickly.
+pub fn fib(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::{ToPrimitive, Zero, One};
+	let num = to_primitive!(num, to_usize, "usize", "fib");
+
+	let mut current = BigInt::zero();
+	let mut next = BigInt::one();
+	for _ in 0..num {
+		let sum = current + &next;
+		current = mem::replace(&mut next, sum);
+	}
+	Ok(BigDecimal::new(current, 0))
+}
+/// Calculates the square root of `num`, rounded down.
+/// Unlike a decimal square root, this is exact no matter how big `num` is.
+/// Fails with `NoConvergence` if it takes more than `sqrt_max_iterations` steps.
+pub fn isqrt(num: BigDecimal, sqrt_max_iterations: u64) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	Ok(BigDecimal::new(checked_sqrt(&num.to_bigint().unwrap(), sqrt_max_iterations)?, 0))
+}
+/// Calculates the square root of `num`, cut off after `scale` decimals.
+/// The square root of a perfect square is exactly the whole number instead.
+/// Fails with `NoConvergence` if it takes more than `sqrt_max_iterations` steps.
+pub fn sqrt(num: BigDecimal, scale: u64, sqrt_max_iterations: u64) -> CalcResult<BigDecimal> {
+	require_positive(&num)?;
+
+	// The square root of a perfect square is exact, so `sqrt(144)` is just 12
+	if num.with_scale(0) == num {
+		use num::bigint::ToBigInt;
+		let int = num.to_bigint().unwrap();
+		let root = checked_sqrt(&int, sqrt_max_iterations)?;
+		if &root * &root == int {
+			return Ok(BigDecimal::new(root, 0));
+		}
+	}
+
+	// sqrt(x * 10^(2p)) = sqrt(x) * 10^p
+	let scale = scale as i64;
+	let (int, _) = num.with_scale(scale * 2).into_bigint_and_exponent();
+	Ok(BigDecimal::new(checked_sqrt(&int, sqrt_max_iterations)?, scale))
+}
+/// Same as `checked_sqrt`, for numbers the approximations made themselves, which are small enough to always settle
+fn bigint_sqrt(num: &BigInt) -> BigInt {
+	checked_sqrt(num, MAX_SQRT_ITERATIONS).unwrap()
+}
+fn checked_sqrt(num: &BigInt, sqrt_max_iterations: u64) -> CalcResult<BigInt> {
+	use num::{One, Zero};
+	if num.is_zero() {
+		return Ok(BigInt::zero());
+	}
+
+	// Newton's method, starting above the root so it decreases until it settles.
+	// It settles once a step doesn't decrease anymore, and it at least halves the distance to the root every step.
+	let mut root = BigInt::one() << (num.bits() / 2 + 1);
+	for _ in 0..sqrt_max_iterations {
+		let next = (&root + num / &root) >> 1;
+		if next >= root {
+			return Ok(root);
+		}
+		root = next;
+	}
+	Err(CalcError::NoConvergence)
+}
+/// Returns 1 if `num` is a prime number, otherwise 0.
+///
+/// This is a Miller-Rabin test with the first 13 primes as witnesses,
+/// which is deterministic for everything below 3317044064679887385961981.
+/// From there on it's only a probable-prime test (that number itself is the first one it gets wrong).
+/// It takes roughly cubic time in the number of digits.
+pub fn is_prime(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	Ok(BigDecimal::from(bigint_is_prime(&num.to_bigint().unwrap()) as u8))
+}
+fn bigint_is_prime(num: &BigInt) -> bool {
+	use num::{Integer, One, Zero};
+	const WITNESSES: [u8; 13] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41];
+
+	if *num < BigInt::from(2) {
+		return false;
+	}
+	for &witness in &WITNESSES {
+		let witness = BigInt::from(witness);
+		if *num == witness {
+			return true;
+		} else if (num % &witness).is_zero() {
+			return false;
+		}
+	}
+
+	// num - 1 = odd * 2^twos
+	let minus_one = num - BigInt::one();
+	let mut odd = minus_one.clone();
+	let mut twos = 0;
+	while odd.is_even() {
+		odd = odd >> 1;
+		twos += 1;
+	}
+
+	'witnesses: for &witness in &WITNESSES {
+		let mut x = bigint_powmod(&BigInt::from(witness), &odd, num);
+		if x.is_one() || x == minus_one {
+			continue;
+		}
+		for _ in 1..twos {
+			x = &x * &x % num;
+			if x == minus_one {
+				continue 'witnesses;
+			}
+		}
+		return false;
+	}
+	true
+}
+/// Counts the distinct prime factors of `num`, like `omega(12)` is 2 (2 and 3).
+/// `omega(1)` is 0, and `omega(0)` is an error since every prime divides 0.
+///
+/// This uses trial division, stopping as soon as what's left is prime.
+/// That's fast as long as at most one prime factor is large, but a number like the product of two
+/// 20 digit primes will effectively never finish.
+pub fn omega(num: BigDecimal) -> CalcResult<BigDecimal> {
+	count_prime_factors(num, true)
+}
+/// Counts the prime factors of `num` with multiplicity, like `bigomega(12)` is 3 (2, 2 and 3).
+/// This has the same limits as `omega`.
+pub fn bigomega(num: BigDecimal) -> CalcResult<BigDecimal> {
+	count_prime_factors(num, false)
+}
+fn count_prime_factors(num: BigDecimal, distinct: bool) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::{Integer, One, Zero};
+	use num::bigint::ToBigInt;
+	let mut num = num.to_bigint().unwrap();
+	if num.is_zero() {
+		return Err(CalcError::OutOfDomain("greater than 0"));
+	}
+
+	let mut count = 0i64;
+	let mut factor = BigInt::from(2);
+	while !num.is_one() {
+		if bigint_is_prime(&num) {
+			count += 1;
+			break;
+		}
+		if (&num % &factor).is_zero() {
+			count += 1;
+			num = num / &factor;
+			while (&num % &factor).is_zero() {
+				if !distinct {
+					count += 1;
+				}
+				num = num / &factor;
+			}
+		}
+		factor = if factor.is_even() { factor + BigInt::one() } else { factor + BigInt::from(2) };
+	}
+	Ok(BigDecimal::from(count))
+}
+/// Calculates `base` to the power of `exp`, modulo `modulus`.
+/// This never calculates the full power, so it's fast even for huge exponents.
+pub fn powmod(base: BigDecimal, exp: BigDecimal, modulus: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&base)?;
+	require_whole(&exp)?;
+	require_whole(&modulus)?;
+	require_positive(&exp)?;
+	require_positive(&modulus)?;
+
+	use num::Zero;
+	use num::bigint::ToBigInt;
+	if modulus.is_zero() {
+		return Err(CalcError::DivideByZero);
+	}
+	Ok(BigDecimal::new(bigint_powmod(
+		&base.to_bigint().unwrap(),
+		&exp.to_bigint().unwrap(),
+		&modulus.to_bigint().unwrap()
+	), 0))
+}
+/// Calculates the modular inverse of `num`, the `x` where `num * x` is 1 modulo `modulus`.
+/// Only exists if `num` and `modulus` have no common factors.
+pub fn invmod(num: BigDecimal, modulus: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_whole(&modulus)?;
+	require_positive(&modulus)?;
+
+	use num::{Integer, One, Zero};
+	use num::bigint::ToBigInt;
+	if modulus.is_zero() {
+		return Err(CalcError::DivideByZero);
+	}
+	let modulus = modulus.to_bigint().unwrap();
+
+	// The extended Euclidean algorithm, only keeping track of the coefficient for `num`
+	let mut old_rem = num.to_bigint().unwrap().mod_floor(&modulus);
+	let mut rem = modulus.clone();
+	let mut old_coef = BigInt::one();
+	let mut coef = BigInt::zero();
+	while !rem.is_zero() {
+		let quotient = &old_rem / &rem;
+		let next_rem = &old_rem - &quotient * &rem;
+		old_rem = mem::replace(&mut rem, next_rem);
+		let next_coef = &old_coef - &quotient * &coef;
+		old_coef = mem::replace(&mut coef, next_coef);
+	}
+
+	if !old_rem.is_one() {
+		return Err(CalcError::NoInverse);
+	}
+	Ok(BigDecimal::new(old_coef.mod_floor(&modulus), 0))
+}
+/// `base` to the power of `exp`, modulo `modulus`, by squaring and multiplying
+fn bigint_powmod(base: &BigInt, exp: &BigInt, modulus: &BigInt) -> BigInt {
+	use num::{Integer, One, Zero};
+
+	let mut result = BigInt::one() % modulus;
+	let mut base = base.mod_floor(modulus);
+	let mut exp = exp.clone();
+	while !exp.is_zero() {
+		if exp.is_odd() {
+			result = result * &base % modulus;
+		}
+		base = &base * &base % modulus;
+		exp = exp >> 1;
+	}
+	result
+}
+/// Converts a result to an `i64`, failing with `NotAWhole` for fractions
+/// and `Overflow` for numbers that don't fit
+pub fn result_to_i64(num: &BigDecimal) -> CalcResult<i64> {
+	use num::ToPrimitive;
+	Ok(to_primitive!(num, to_i64, "i64", "result_to_i64"))
+}
+/// Converts a result to the closest `f64`, failing with `Overflow` for numbers too large for one
+pub fn result_to_f64(num: &BigDecimal) -> CalcResult<f64> {
+	// Going through the string rounds correctly, unlike dividing by a power of 10 in floating point
+	match num.to_string().parse::<f64>() {
+		Ok(float) if float.is_finite() => Ok(float),
+		_ => Err(CalcError::Overflow {
+			operation: String::from("result_to_f64"),
+			value: num.to_string(),
+			target: "f64"
+		})
+	}
+}
+/// Recovers the numerator and denominator of `value`, in lowest terms.
+/// This is exact because a `BigDecimal` is always a terminating decimal,
+/// but something like `1/3` has already been cut off by the division.
+pub fn to_fraction(value: &BigDecimal) -> (BigInt, BigInt) {
+	use num::One;
+	let (numerator, scale) = value.as_bigint_and_exponent();
+	if scale <= 0 {
+		return (numerator * num::pow(BigInt::from(10), -scale as usize), BigInt::one());
+	}
+
+	let denominator = num::pow(BigInt::from(10), scale as usize);
+	reduce(numerator, denominator)
+}
+/// Brings the fraction `num / den` to lowest terms, like `6/8` to `3/4`.
+/// The sign ends up on the numerator, so the denominator is never negative.
+/// `0/0` is returned unchanged, since there is nothing to divide by.
+pub fn reduce(num: BigInt, den: BigInt) -> (BigInt, BigInt) {
+	use num::{Integer, Signed, Zero};
+	let mut gcd = num.gcd(&den);
+	if gcd.is_zero() {
+		return (num, den);
+	}
+	if den.is_negative() {
+		gcd = -gcd;
+	}
+	(num / &gcd, den / gcd)
+}
+/// Counts the set bits in `num`, like `popcount(7)` is 3
+pub fn popcount(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	let (_, bytes) = num.to_bigint().unwrap().to_bytes_le();
+	let count: u32 = bytes.iter().map(|byte| byte.count_ones()).sum();
+	Ok(BigDecimal::from(count as i64))
+}
+/// Returns how many bits it takes to write `num`, which is the position of the highest set bit.
+/// `bitlen(8)` is 4, and `bitlen(0)` is 0.
+pub fn bitlen(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	Ok(BigDecimal::from(num.to_bigint().unwrap().bits() as i64))
+}
+/// Rotates `value` left by `bits` within a `width` bits wide number, so bits shifted out on the left come back on the right.
+/// Any bits of `value` above `width` are dropped first, like they would be in a fixed-size register.
+pub fn rotl(value: BigDecimal, bits: BigDecimal, width: BigDecimal) -> CalcResult<BigDecimal> {
+	rotate(value, bits, width, false)
+}
+/// Rotates `value` right by `bits` within a `width` bits wide number. See `rotl`.
+pub fn rotr(value: BigDecimal, bits: BigDecimal, width: BigDecimal) -> CalcResult<BigDecimal> {
+	rotate(value, bits, width, true)
+}
+fn rotate(value: BigDecimal, bits: BigDecimal, width: BigDecimal, right: bool) -> CalcResult<BigDecimal> {
+	for num in &[&value, &bits, &width] {
+		require_whole(num)?;
+		require_positive(num)?;
+	}
+
+	use num::{BigUint, One, ToPrimitive, Zero};
+	use num::bigint::ToBigInt;
+	let width = to_primitive!(width, to_usize, "usize", if right { "rotr" } else { "rotl" });
+	if width == 0 {
+		return Ok(BigDecimal::zero());
+	}
+
+	let mask = (BigUint::one() << width) - BigUint::one();
+	let value = value.to_bigint().unwrap().to_biguint().unwrap() & &mask;
+	let mut bits = (bits.to_bigint().unwrap() % BigInt::from(width)).to_usize().unwrap();
+	if right {
+		bits = (width - bits) % width;
+	}
+
+	let rotated = ((&value << bits) | (value >> (width - bits))) & mask;
+	Ok(BigDecimal::new(BigInt::from_biguint(Sign::Plus, rotated), 0))
+}
+/// Adds up the decimal digits of `num`, like `digitsum(123)` is 6
+pub fn digitsum(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	let sum: u64 = num.to_bigint().unwrap().to_str_radix(10).bytes().map(|digit| (digit - b'0') as u64).sum();
+	Ok(BigDecimal::from(sum as i64))
+}
+/// Sums the decimal digits of `num` over and over until only one digit is left, like `digroot(9875)` is 2
+/// (9+8+7+5 = 29, 2+9 = 11, 1+1 = 2).
+/// This uses `1 + (num - 1) mod 9` instead of actually summing, so it's fast for huge numbers too.
+pub fn digroot(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::{One, Zero};
+	use num::bigint::ToBigInt;
+	let num = num.to_bigint().unwrap();
+	if num.is_zero() {
+		return Ok(BigDecimal::zero());
+	}
+	Ok(BigDecimal::new(BigInt::one() + (num - BigInt::one()) % BigInt::from(9), 0))
+}
+/// Splits `num` into its significant digits as a whole number and a power of 10,
+/// so 1.50 is (15, -1) and 1500 is (15, 2). Zero is (0, 0).
+pub fn decompose(num: &BigDecimal) -> (BigInt, i64) {
+	use num::Zero;
+	let (int, scale) = num.as_bigint_and_exponent();
+	if int.is_zero() {
+		return (int, 0);
+	}
+	let digits = int.to_str_radix(10);
+	let zeros = digits.len() - digits.trim_end_matches('0').len();
+	(int / num::pow(BigInt::from(10), zeros), zeros as i64 - scale)
+}
+/// Calculates the logarithm of `num` in `base`, rounded down, like `ilog(2, 1000)` is 9.
+/// This divides by `base` until nothing is left instead of using a decimal logarithm,
+/// so it's exact even right below a power of `base`.
+pub fn ilog(base: BigDecimal, num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&base)?;
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::{Signed, Zero};
+	use num::bigint::ToBigInt;
+	if base < BigDecimal::from(2) {
+		return Err(CalcError::OutOfDomain("a base of at least 2"));
+	}
+	if !num.is_positive() {
+		return Err(CalcError::OutOfDomain("greater than 0"));
+	}
+
+	let base = base.to_bigint().unwrap();
+	let mut num = num.to_bigint().unwrap() / &base;
+	let mut log = 0u64;
+	while !num.is_zero() {
+		num = num / &base;
+		log += 1;
+	}
+	Ok(BigDecimal::from(log))
+}
+/// Reverses the decimal digits of `num`, like `reverse(123)` is 321.
+/// The result is a number, so trailing zeros become leading zeros and disappear: `reverse(120)` is 21.
+pub fn reverse(num: BigDecimal) -> CalcResult<BigDecimal> {
+	require_whole(&num)?;
+	require_positive(&num)?;
+
+	use num::bigint::ToBigInt;
+	let reversed: String = num.to_bigint().unwrap().to_str_radix(10).chars().rev().collect();
+	Ok(BigDecimal::new(reversed.parse().unwrap(), 0))
+}
+/// Calculates pi, cut off after `scale` decimals
+pub fn pi(scale: u64) -> BigDecimal {
+	let one = fixed_one(scale);
+	from_fixed(fixed_pi(&one), scale)
+}
+/// Calculates tau (2pi, a full turn in radians), cut off after `scale` decimals
+pub fn tau(scale: u64) -> BigDecimal {
+	let one = fixed_one(scale);
+	from_fixed(fixed_pi(&one) * 2, scale)
+}
+/// Turns `num` radians into an angle from 0 up to (but not including) tau with the same direction,
+/// like `normalize_angle(3pi)` is pi and `normalize_angle(-pi/2)` is 3pi/2. Cut off after `scale` decimals.
+pub fn normalize_angle(num: BigDecimal, scale: u64) -> BigDecimal {
+	let (angle, _, work) = fixed_turn(&num, scale);
+	from_fixed(angle, work).with_scale(scale as i64)
+}
+/// Converts `num` radians to degrees, cut off after `scale` decimals
+pub fn todeg(num: BigDecimal, scale: u64) -> BigDecimal {
+	let work = scale + log10(&num).max(0.0) as u64 + 3;
+	let one = fixed_one(work);
+	from_fixed(to_fixed(&(num * BigDecimal::from(180)), work) * &one / fixed_pi(&one), work).with_scale(scale as i64)
+}
+/// Converts `num` degrees to radians, cut off after `scale` decimals
+pub fn torad(num: BigDecimal, scale: u64) -> BigDecimal {
+	let work = scale + log10(&num).max(0.0) as u64 + 1;
+	let one = fixed_one(work);
+	from_fixed(to_fixed(&num, work) * fixed_pi(&one) / (&one * BigInt::from(180)), work).with_scale(scale as i64)
+}
+/// Calculates the sine of `num` radians, cut off after `scale` decimals
+pub fn sin(num: BigDecimal, scale: u64) -> BigDecimal {
+	let (angle, work) = reduce_angle(&num, scale);
+	let one = fixed_one(work);
+	from_fixed(fixed_sin(&angle, &one), work).with_scale(scale as i64)
+}
+/// Calculates the cosine of `num` radians, cut off after `scale` decimals
+pub fn cos(num: BigDecimal, scale: u64) -> BigDecimal {
+	let (angle, work) = reduce_angle(&num, scale);
+	let one = fixed_one(work);
+	from_fixed(fixed_cos(&angle, &one), work).with_scale(scale as i64)
+}
+/// Calculates the tangent of `num` radians, cut off after `scale` decimals
+pub fn tan(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::Zero;
+	let (angle, work) = reduce_angle(&num, scale);
+	let one = fixed_one(work);
+	let cos = fixed_cos(&angle, &one);
+	if cos.is_zero() {
+		return Err(CalcError::OutOfDomain("anything but an odd multiple of pi/2"));
+	}
+	Ok(from_fixed(fixed_sin(&angle, &one) * &one / cos, work).with_scale(scale as i64))
+}
+/// Calculates the arctangent of `num` in radians, cut off after `scale` decimals
+pub fn atan(num: BigDecimal, scale: u64) -> BigDecimal {
+	let one = fixed_one(scale);
+	from_fixed(fixed_atan(&to_fixed(&num, scale), &one), scale)
+}
+/// Calculates the arcsine of `num` in radians, cut off after `scale` decimals.
+/// `num` must be between -1 and 1.
+pub fn asin(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	let one = fixed_one(scale);
+	Ok(from_fixed(fixed_asin(&to_fixed(&num, scale), &one)?, scale))
+}
+/// Calculates the arccosine of `num` in radians, cut off after `scale` decimals.
+/// `num` must be between -1 and 1.
+pub fn acos(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	let one = fixed_one(scale);
+	// acos(x) = pi/2 - asin(x)
+	Ok(from_fixed((fixed_pi(&one) >> 1) - fixed_asin(&to_fixed(&num, scale), &one)?, scale))
+}
+/// Calculates the angle in radians between the positive x axis and the point (`x`, `y`), from -pi to pi,
+/// cut off after `scale` decimals. `atan2(0, 0)` is 0.
+pub fn atan2(y: BigDecimal, x: BigDecimal, scale: u64) -> BigDecimal {
+	use num::{Signed, Zero};
+	let one = fixed_one(scale);
+
+	if x.is_zero() {
+		let half_pi = fixed_pi(&one) >> 1;
+		return match y.sign() {
+			Sign::Plus => from_fixed(half_pi, scale),
+			Sign::Minus => from_fixed(-half_pi, scale),
+			Sign::NoSign => BigDecimal::zero()
+		};
+	}
+
+	let ratio = div(&y, &x, scale + GUARD_DIGITS);
+	let angle = fixed_atan(&to_fixed(&ratio, scale), &one);
+	if x.is_positive() {
+		from_fixed(angle, scale)
+	} else if y.is_negative() {
+		from_fixed(angle - fixed_pi(&one), scale)
+	} else {
+		from_fixed(angle + fixed_pi(&one), scale)
+	}
+}
+
+/// Calculates e to the power of `num`, cut off after `scale` decimals
+pub fn exp(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::{Signed, ToPrimitive, Zero};
+
+	if num.is_negative() {
+		// e^-x is below 10^-scale once x > scale * ln(10), and ln(10) < 3
+		if -&num > BigDecimal::from(3 * (scale as i64 + 1)) {
+			return Ok(BigDecimal::zero().with_scale(scale as i64));
+		}
+		use num::One;
+		let positive = exp(-num, scale + GUARD_DIGITS)?;
+		return Ok(div(&BigDecimal::one(), &positive, scale));
+	}
+
+	let whole = num.with_scale(0);
+	let whole = to_primitive!(whole, to_u64, "u64", "exp");
+
+	// e^x = (e^(x / 2^k))^2^k, and the series converges fast once x / 2^k is small.
+	// Every squaring doubles the error, and e^x has about x/2 digits before the decimal point,
+	// so those need to be calculated on top of `scale`.
+	let halvings = 64 - whole.leading_zeros() as usize + 8;
+	let work = scale + whole / 2 + halvings as u64 / 3 + 1;
+	let one = fixed_one(work);
+	let reduced = to_fixed(&num, work) >> halvings;
+
+	// e^x = 1 + x + x^2/2! + x^3/3! + ...
+	let mut term = one.clone();
+	let mut sum = one.clone();
+	let mut n = 0;
+	loop {
+		n += 1;
+		term = term * &reduced / (&one * BigInt::from(n));
+		if term.is_zero() {
+			break;
+		}
+		sum = sum + &term;
+	}
+	for _ in 0..halvings {
+		sum = &sum * &sum / &one;
+	}
+
+	Ok(from_fixed(sum, work).with_scale(scale as i64))
+}
+/// Calculates the natural logarithm of `num`, cut off after `scale` decimals
+pub fn ln(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::Signed;
+	if !num.is_positive() {
+		return Err(CalcError::OutOfDomain("greater than 0"));
+	}
+
+	// Numbers below 1 don't have enough digits at the fixed scale, so use ln(x) = -ln(1/x) for those
+	use num::One;
+	if num < BigDecimal::one() {
+		let digits = (-log10(&num)).ceil() as u64;
+		let inverse = div(&BigDecimal::one(), &num, scale + digits + GUARD_DIGITS);
+		return Ok(-ln(inverse, scale)?);
+	}
+	let one = fixed_one(scale);
+	Ok(from_fixed(fixed_ln(&to_fixed(&num, scale), &one), scale))
+}
+/// Calculates the hyperbolic sine of `num`, cut off after `scale` decimals
+pub fn sinh(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	// sinh(x) = (e^x - e^-x) / 2
+	let positive = exp(num.clone(), scale + GUARD_DIGITS)?;
+	let negative = exp(-num, scale + GUARD_DIGITS)?;
+	Ok(((positive - negative) / BigDecimal::from(2)).with_scale(scale as i64))
+}
+/// Calculates the hyperbolic cosine of `num`, cut off after `scale` decimals
+pub fn cosh(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	// cosh(x) = (e^x + e^-x) / 2
+	let positive = exp(num.clone(), scale + GUARD_DIGITS)?;
+	let negative = exp(-num, scale + GUARD_DIGITS)?;
+	Ok(((positive + negative) / BigDecimal::from(2)).with_scale(scale as i64))
+}
+/// Calculates the hyperbolic tangent of `num`, cut off after `scale` decimals
+pub fn tanh(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::{One, Signed};
+
+	// tanh(x) = (1 - e^-2x) / (1 + e^-2x), using e^-2|x| so it never gets large
+	let small = exp(num.abs() * BigDecimal::from(-2), scale + GUARD_DIGITS)?;
+	let result = div(&(BigDecimal::one() - &small), &(BigDecimal::one() + small), scale);
+	Ok(if num.is_negative() { -result } else { result })
+}
+/// Calculates the gamma function, which extends the factorial to fractions: `gamma(x)` is `(x - 1)!`,
+/// so `gamma(5)` is 24 and `gamma(0.5)` is `sqrt(pi)`. Cut off after `scale` decimals.
+/// Whole numbers use the exact factorial, everything else Spouge's approximation with enough terms
+/// for `scale` correct decimals. It isn't defined for 0 and negative whole numbers.
+pub fn gamma(num: BigDecimal, scale: u64) -> CalcResult<BigDecimal> {
+	use num::{One, Signed};
+
+	if num.with_scale(0) == num {
+		if !num.is_positive() {
+			return Err(CalcError::OutOfDomain("anything but 0 or a negative whole number"));
+		}
+		return factorial(num - BigDecimal::one(), None);
+	}
+	if num.is_negative() {
+		// gamma(x) = gamma(x + 1) / x, until the argument is positive
+		let mut shifted = num;
+		let mut product = BigDecimal::one();
+		while shifted.is_negative() {
+			product *= &shifted;
+			shifted += BigDecimal::one();
+		}
+		// Dividing by a product below 1 moves the error up by one digit per zero after the decimal point
+		let (digits, product_scale) = product.as_bigint_and_exponent();
+		let zeros = product_scale - digits.abs().to_str_radix(10).len() as i64;
+		let result = gamma(shifted, scale + zeros.max(0) as u64 + 1)?;
+		return Ok(div(&result, &product, scale));
+	}
+
+	// The digits before the decimal point need to be correct too. Stirling's approximation is close enough to count them.
+	let x = result_to_f64(&num)?;
+	let magnitude = if x > 2.0 { ((x - 0.5) * x.ln() - x + 1.0) / std::f64::consts::LN_10 } else { 0.0 };
+	let digits = scale + magnitude.ceil() as u64;
+
+	// Spouge's approximation, with z = x - 1 and `terms` as a:
+	// gamma(z + 1) = (z + a)^(z + 1/2) e^-(z + a) (c_0 + sum for k from 1 to a - 1 of c_k / (z + k))
+	// where c_0 = sqrt(2pi) and c_k = (-1)^(k - 1) (a - k)^(k - 1/2) e^(a - k) / (k - 1)!
+	// The relative error is below (2pi)^-(a + 1/2), and 2pi is more than 10^0.79.
+	// The c_k are a lot larger than the result and cancel out, which costs about a digits of precision.
+	let terms = digits * 127 / 100 + 2;
+	let work = digits + terms;
+	let one = fixed_one(work);
+	let z = to_fixed(&(num - BigDecimal::one()), work);
+
+	let e = to_fixed(&exp(BigDecimal::one(), work)?, work);
+	let mut e_powers = vec![one.clone()];
+	for i in 1..terms as usize {
+		let power = &e_powers[i - 1] * &e / &one;
+		e_powers.push(power);
+	}
+
+	let mut sum = bigint_sqrt(&(fixed_pi(&one) * 2 * &one));
+	let mut k_factorial = BigInt::one();
+	for k in 1..terms {
+		if k > 1 {
+			k_factorial = k_factorial * BigInt::from(k - 1);
+		}
+		let base = terms - k;
+		let root = bigint_sqrt(&(&one * &one * BigInt::from(base)));
+		let c = &e_powers[base as usize] * root / &one * num::pow(BigInt::from(base), (k - 1) as usize) / &k_factorial;
+		let term = c * &one / (&z + &one * BigInt::from(k));
+		sum = if k % 2 == 1 { sum + term } else { sum - term };
+	}
+
+	// (z + a)^(z + 1/2) e^-(z + a) = e^((z + 1/2) ln(z + a) - (z + a))
+	let shifted = &z + &one * BigInt::from(terms);
+	let power = (&z + (&one >> 1)) * fixed_ln(&shifted, &one) / &one - shifted;
+	let factor = to_fixed(&exp(from_fixed(power, work), work)?, work);
+
+	Ok(from_fixed(factor * sum / &one, work).with_scale(scale as i64))
+}
+
+// Series like the one for atan are calculated on integers scaled by 10^(scale + GUARD_DIGITS),
+// so rounding errors stay in the extra digits that are cut off at the end
+const GUARD_DIGITS: u64 = 10;
+
+fn fixed_one(scale: u64) -> BigInt {
+	num::pow(BigInt::from(10), (scale + GUARD_DIGITS) as usize)
+}
+fn to_fixed(num: &BigDecimal, scale: u64) -> BigInt {
+	num.with_scale((scale + GUARD_DIGITS) as i64).into_bigint_and_exponent().0
+}
+fn from_fixed(num: BigInt, scale: u64) -> BigDecimal {
+	BigDecimal::new(num, (scale + GUARD_DIGITS) as i64).with_scale(scale as i64)
+}
+fn fixed_pi(one: &BigInt) -> BigInt {
+	// Machin's formula: pi = 16 atan(1/5) - 4 atan(1/239)
+	fixed_atan(&(one / BigInt::from(5)), one) * 16 - fixed_atan(&(one / BigInt::from(239)), one) * 4
+}
+/// Adds or subtracts whole turns from `num` until it's from 0 up to (but not including) tau.
+/// Returns the angle, tau, and the scale both are fixed at, which is higher than `scale`:
+/// every digit before the decimal point multiplies the error in tau by 10, and needs another digit to make up for it.
+fn fixed_turn(num: &BigDecimal, scale: u64) -> (BigInt, BigInt, u64) {
+	use num::Integer;
+	let work = scale + log10(num).max(0.0) as u64 + 1;
+	let one = fixed_one(work);
+	let tau = fixed_pi(&one) * 2;
+	(to_fixed(num, work).mod_floor(&tau), tau, work)
+}
+/// Same as `fixed_turn`, but between -pi and pi, so the series for sine and cosine converge fast even for huge angles
+fn reduce_angle(num: &BigDecimal, scale: u64) -> (BigInt, u64) {
+	let (angle, tau, work) = fixed_turn(num, scale);
+	(if &angle * 2 > tau { angle - tau } else { angle }, work)
+}
+fn fixed_sin(num: &BigInt, one: &BigInt) -> BigInt {
+	use num::Zero;
+
+	// sin(x) = x - x^3/3! + x^5/5! - ...
+	let square = num * num / one;
+	let mut term = num.clone();
+	let mut sum = num.clone();
+	let mut n = 1;
+	loop {
+		term = -(term * &square / one) / BigInt::from((n + 1) * (n + 2));
+		n += 2;
+		if term.is_zero() {
+			break;
+		}
+		sum = sum + &term;
+	}
+	sum
+}
+fn fixed_cos(num: &BigInt, one: &BigInt) -> BigInt {
+	use num::Zero;
+
+	// cos(x) = 1 - x^2/2! + x^4/4! - ...
+	let square = num * num / one;
+	let mut term = one.clone();
+	let mut sum = one.clone();
+	let mut n = 0;
+	loop {
+		term = -(term * &square / one) / BigInt::from((n + 1) * (n + 2));
+		n += 2;
+		if term.is_zero() {
+			break;
+		}
+		sum = sum + &term;
+	}
+	sum
+}
+fn fixed_asin(num: &BigInt, one: &BigInt) -> CalcResult<BigInt> {
+	use num::Signed;
+	if num.abs() > *one {
+		return Err(CalcError::OutOfDomain("between -1 and 1"));
+	}
+	if num.abs() == *one {
+		let half_pi = fixed_pi(one) >> 1;
+		return Ok(if num.is_positive() { half_pi } else { -half_pi });
+	}
+
+	// asin(x) = atan(x / sqrt(1 - x^2))
+	Ok(fixed_atan(&(num * one / bigint_sqrt(&(one * one - num * num))), one))
+}
+fn fixed_atan(num: &BigInt, one: &BigInt) -> BigInt {
+	use num::{Signed, Zero};
+
+	// atan(x) = pi/2 - atan(1/x) for positive x, so the series only ever sees numbers up to 1
+	if num.abs() > *one {
+		let half_pi = fixed_pi(one) >> 1;
+		let rest = fixed_atan(&(one * one / num), one);
+		return if num.is_positive() { half_pi - rest } else { -half_pi - rest };
+	}
+
+	// atan(x) = 2 atan(x / (1 + sqrt(1 + x^2))), which makes the series converge a lot faster
+	let mut num = num.clone();
+	let mut halvings = 0;
+	while num.abs() * 10 > *one {
+		num = &num * one / (one + bigint_sqrt(&(one * one + &num * &num)));
+		halvings += 1;
+	}
+
+	// atan(x) = x - x^3/3 + x^5/5 - ...
+	let square = &num * &num / one;
+	let mut power = num.clone();
+	let mut sum = num;
+	let mut n = 1;
+	loop {
+		power = -(power * &square / one);
+		n += 2;
+		let term = &power / BigInt::from(n);
+		if term.is_zero() {
+			break;
+		}
+		sum = sum + term;
+	}
+	sum << halvings
+}
+fn fixed_ln(num: &BigInt, one: &BigInt) -> BigInt {
+	// ln(x) = k ln(2) + ln(x / 2^k), with k picked so x / 2^k is close to 1
+	let k = num.bits() as i64 - one.bits() as i64;
+	let reduced = if k >= 0 { num >> k as usize } else { num << -k as usize };
+	let ln_2 = fixed_atanh(&(one / BigInt::from(3)), one) * 2;
+
+	// ln(x) = 2 atanh((x - 1) / (x + 1))
+	fixed_atanh(&((&reduced - one) * one / (&reduced + one)), one) * 2 + ln_2 * BigInt::from(k)
+}
+fn fixed_atanh(num: &BigInt, one: &BigInt) -> BigInt {
+	use num::Zero;
+
+	// atanh(x) = x + x^3/3 + x^5/5 + ...
+	let square = num * num / one;
+	let mut power = num.clone();
+	let mut sum = num.clone();
+	let mut n = 1;
+	loop {
+		power = power * &square / one;
+		n += 2;
+		let term = &power / BigInt::from(n);
+		if term.is_zero() {
+			break;
+		}
+		sum = sum + term;
+	}
+	sum
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn calc(input: &str) -> CalcResult<BigDecimal> {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		::parse_and_calc(input, &mut variables, &mut functions)
+	}
+	fn num(num: &str) -> BigDecimal {
+		num.parse().unwrap()
+	}
+	/// Whether `input` calculates to about the same as `expected`, for approximations that may be off in the last digits
+	fn close(input: &str, expected: &str) -> bool {
+		use num::Signed;
+		(calc(input).unwrap() - calc(expected).unwrap()).abs() < num("1e-90")
+	}
+	/// Same as `calc`, but lets `setup` change the context first, like setting a limit
+	fn calc_with<F>(input: &str, setup: F) -> CalcResult<BigDecimal>
+		where F: FnOnce(&mut Context<::std::vec::IntoIter<Token>>) {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let mut context = Context::new(::parser::parse(input)?.into_iter().peekable(), &mut variables, &mut functions);
+		setup(&mut context);
+		calculate_all(&mut context)
+	}
+
+	#[test]
+	fn sum_range() {
+		assert_eq!(calc("sum_range(i, 1, 5, i*i)").unwrap(), num("55"));
+		assert_eq!(calc("product_range(i, 1, 5, i)").unwrap(), num("120"));
+		assert_eq!(calc("product_range(i, 3, 1, i)").unwrap(), num("1"));
+		assert_eq!(calc("i = 7; sum_range(i, 1, 3, i); i").unwrap(), num("7"));
+		assert!(matches!(calc("sum_range(i, 1, 5)"), Err(CalcError::IncorrectArguments(4, 3))));
+	}
+	#[test]
+	fn sum_with_variable_first() {
+		assert_eq!(calc("x = 10; sum(x, 1, 2, 3)").unwrap(), num("16"));
+		assert_eq!(calc("x = 10; product(x, 1, 2, 3)").unwrap(), num("60"));
+	}
+	#[test]
+	fn range_in_sum() {
+		// Written the way a ranged sum is elsewhere, `sum` points at `sum_range` instead of adding up 4 numbers
+		for input in &["sum(i, 1, 5, i*i)", "i = 2; sum(i, 1, 5, i*i)", "PRODUCT(k, 1, 5, k)"] {
+			assert!(matches!(calc(input), Err(CalcError::RangeInSum(_))), "{}", input);
+		}
+		let message = calc("sum(i, 1, 5, i*i)").unwrap_err().to_string();
+		assert!(message.contains("sum_range(i, 1, 5, i*i)"), "{}", message);
+		assert_eq!(calc("i = 2; sum(i, 1, 5, 2*3)").unwrap(), num("14"));
+		assert_eq!(calc("sum(1, 2, 3, 1 / 0 * 0 + 4)").unwrap_err().category(), ErrorCategory::Domain);
+	}
+	#[test]
+	fn constants() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		variables.insert("e".to_string(), num("5"));
+		assert_eq!(::parse_and_calc("e + pi", &mut variables, &mut functions).unwrap(), num("5") + num(CONSTANTS[0].1));
+		assert_eq!(variables.len(), 1);
+
+		assert!(matches!(calc("pi = 3"), Err(CalcError::ReadOnly(_))));
+		assert_eq!(calc("|tau - 2*pi| < 1e-99").unwrap(), num("1"));
+		assert_eq!(calc("2exp(1)").unwrap(), calc("2 * e").unwrap());
+	}
+	#[test]
+	fn guarded_recursion() {
+		assert_eq!(calc("n = 5; c = 0; g = ((n = n - 1) > 0 && (c = c + 1) && g()); g(); c").unwrap(), num("4"));
+		assert_eq!(calc("f = ($1 <= 1 || f($1 - 1)); f(10)").unwrap(), num("1"));
+
+		// When a variable keeps changing the calculator can't tell if it ever ends, so it goes as deep
+		// as `TooDeep` allows, which needs more than the default test stack
+		let endless = std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(|| {
+			matches!(calc("c = 0; f = ((c = c + 1) && f($1)); f(1)"), Err(CalcError::TooDeep))
+		}).unwrap();
+		assert!(endless.join().unwrap());
+	}
+	#[test]
+	fn assignment_value() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		assert_eq!(::parse_and_calc("x = 5", &mut variables, &mut functions).unwrap(), num("5"));
+		assert_eq!(variables["x"], num("5"));
+
+		assert_eq!(::parse_and_calc("y = (x = 3) + 1", &mut variables, &mut functions).unwrap(), num("4"));
+		assert_eq!(variables["x"], num("3"));
+		assert_eq!(variables["y"], num("4"));
+		assert!(functions.is_empty());
+
+		assert_eq!(calc("f = ($1 * 2); f(4)").unwrap(), num("8"));
+		assert_eq!(calc("|y = (2) - 5| + y").unwrap(), num("0"));
+	}
+	#[test]
+	fn sqrt_max_iterations() {
+		assert!(matches!(sqrt(num("2"), 10, 1), Err(CalcError::NoConvergence)));
+		assert_eq!(sqrt(num("2"), 10, MAX_SQRT_ITERATIONS).unwrap(), num("1.4142135623"));
+		assert!(matches!(isqrt(num("1000000"), 1), Err(CalcError::NoConvergence)));
+		assert_eq!(isqrt(num("1000000"), MAX_SQRT_ITERATIONS).unwrap(), num("1000"));
+
+		// The series don't need the limit, they end for any input
+		let limited = |input| calc_with(input, |context| context.sqrt_max_iterations = 1);
+		assert!(matches!(limited("sqrt(2)"), Err(CalcError::NoConvergence)));
+		for input in &["ln(1e50)", "ln(1e-50)", "exp(1000)", "exp(-1000)", "sin(1e20)", "cos(-1e20)", "atan(1e50)"] {
+			assert!(limited(input).is_ok(), "{}", input);
+		}
+	}
+	#[test]
+	fn verbose_covers_every_statement() {
+		fn verbose(input: &str) -> Calculated {
+			let mut variables = HashMap::new();
+			let mut functions = HashMap::new();
+			let mut context = Context::new(::parser::parse(input).unwrap().into_iter().peekable(), &mut variables, &mut functions);
+			calculate_verbose(&mut context).unwrap()
+		}
+		let calculated = verbose("a = 1; a / 4");
+		assert_eq!(calculated.value, num("0.25"));
+		assert!(calculated.warnings.is_empty());
+
+		let calculated = verbose("a = 1; a / 3");
+		assert_eq!(calculated.value, calc("1 / 3").unwrap());
+		assert_eq!(calculated.warnings, vec![CalcWarning::Truncated(PRECISION as u64)]);
+		// A warning from an earlier statement isn't lost
+		assert_eq!(verbose("a = 1 / 3; 2").warnings, vec![CalcWarning::Truncated(PRECISION as u64)]);
+	}
+	#[test]
+	fn percent() {
+		assert_eq!(calc("200 * 15%").unwrap(), num("30"));
+		// The percent is just a number, so it binds like one: 100 + 0.1, not 100 plus 10% of 100
+		assert_eq!(calc("100 + 10%").unwrap(), num("100.1"));
+		assert_eq!(calc("7 % 3").unwrap(), num("1"));
+		// A sign, `|` or `!` after it starts a second operand too
+		assert_eq!(calc("7 % -3").unwrap(), calc("7 % (-3)").unwrap());
+		assert_eq!(calc("7 % +3").unwrap(), num("1"));
+		assert_eq!(calc("x = 2; 7 % |x|").unwrap(), num("1"));
+		assert_eq!(calc("7 % !0").unwrap(), num("0"));
+		assert_eq!(calc("10% || 0").unwrap(), num("1"));
+	}
+	#[test]
+	fn implicit_multiplication() {
+		assert_eq!(calc("2(3 + 4)").unwrap(), num("14"));
+		assert_eq!(calc("x = 3; 2x").unwrap(), num("6"));
+		assert_eq!(calc("2pi").unwrap(), calc("2 * pi").unwrap());
+		// Only a number in front multiplies, a name in front of a parenthesis is always a call
+		assert_eq!(calc("f = ($1 * 2); f(3)").unwrap(), num("6"));
+		assert_eq!(calc("f = ($1 * 2); 2f(3)").unwrap(), num("12"));
+		assert!(matches!(calc("x = 2; x(3)"), Err(CalcError::UnknownFunction(ref name, _)) if name == "x"));
+	}
+	#[test]
+	fn unary_plus() {
+		assert_eq!(calc("+-3").unwrap(), num("-3"));
+		assert_eq!(calc("+5").unwrap(), num("5"));
+		assert_eq!(calc("3 * +2").unwrap(), num("6"));
+		assert!(matches!(calc("1 +"), Err(CalcError::InvalidSyntax(_))));
+	}
+	#[test]
+	fn error_positions() {
+		let at = |column| Position { line: 1, column };
+		assert!(matches!(calc("1 +* 2"), Err(CalcError::InvalidSyntax(pos)) if pos == at(4)));
+		assert!(matches!(calc("1 + x"), Err(CalcError::UnknownVariable(ref name, pos)) if name == "x" && pos == at(5)));
+		assert!(matches!(calc("12 3)"), Err(CalcError::ExpectedEOF(Token::ParenClose, pos)) if pos == at(5)));
+		assert_eq!(calc("1 + x").unwrap_err().to_string(), "Unknown variable \"x\" at column 5");
+	}
+	#[test]
+	fn error_lines() {
+		assert!(matches!(calc("x = 1\n foo(2)"), Err(CalcError::UnknownFunction(ref name, Position { line: 2, column: 2 })) if name == "foo"));
+		assert!(calc("x = 1\n foo(2)").unwrap_err().to_string().starts_with("Unknown function \"foo\" at line 2, column 2\n"));
+		assert_eq!(calc("x = 1\r\ny +\n  z").unwrap_err().to_string(), "Unknown variable \"y\" at line 2, column 1");
+		assert_eq!(calc("1\n2\n3 +* 4").unwrap_err().to_string(), "Invalid syntax at line 3, column 4");
+		// Errors in a function body point at the call
+		assert_eq!(calc("f = ($1 + y)\n1 + f(2)").unwrap_err().to_string(), "Unknown variable \"y\" at line 2, column 5");
+	}
+	#[test]
+	fn pow_negative_base() {
+		assert_eq!(calc("pow(-2, 3)").unwrap(), num("-8"));
+		assert_eq!(calc("pow(-2, 2)").unwrap(), num("4"));
+		assert_eq!(calc("pow(-0.5, 3)").unwrap(), num("-0.125"));
+		assert!(matches!(calc("pow(-2, 0.5)"), Err(CalcError::OutOfDomain(_))));
+	}
+	#[test]
+	fn fractions() {
+		fn fraction(input: &str) -> (BigInt, BigInt) {
+			to_fraction(&num(input))
+		}
+		assert_eq!(fraction("0.75"), (BigInt::from(3), BigInt::from(4)));
+		assert_eq!(fraction("-2.50"), (BigInt::from(-5), BigInt::from(2)));
+		assert_eq!(fraction("3"), (BigInt::from(3), BigInt::from(1)));
+		assert_eq!(fraction("0"), (BigInt::from(0), BigInt::from(1)));
+		assert_eq!(to_fraction(&BigDecimal::new(BigInt::from(12), -2)), (BigInt::from(1200), BigInt::from(1)));
+	}
+	#[test]
+	fn sum_and_product() {
+		assert_eq!(calc("sum(1, 2, 3)").unwrap(), num("6"));
+		assert_eq!(calc("product(2, 3, 4)").unwrap(), num("24"));
+		assert_eq!(calc("sum(0.5, -1)").unwrap(), num("-0.5"));
+		assert_eq!(calc("sum()").unwrap(), num("0"));
+		assert_eq!(calc("product()").unwrap(), num("1"));
+	}
+	#[test]
+	fn fib() {
+		assert_eq!(calc("fib(0)").unwrap(), num("0"));
+		assert_eq!(calc("fib(1)").unwrap(), num("1"));
+		assert_eq!(calc("fib(10)").unwrap(), num("55"));
+		assert_eq!(calc("fib(100)").unwrap(), num("354224848179261915075"));
+		assert!(matches!(calc("fib(-1)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("fib(1.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn integer_sqrt() {
+		assert_eq!(calc("isqrt(15)").unwrap(), num("3"));
+		assert_eq!(calc("isqrt(16)").unwrap(), num("4"));
+		assert_eq!(calc("isqrt(0)").unwrap(), num("0"));
+		assert_eq!(calc("isqrt(100000000000000000000000000000000000000000)").unwrap(), num("316227766016837933199"));
+		assert!(matches!(calc("isqrt(-1)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("isqrt(2.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn hypotenuse() {
+		assert_eq!(calc("hypot(3, 4)").unwrap(), num("5"));
+		assert_eq!(calc("hypot(-5, 12)").unwrap(), num("13"));
+		assert_eq!(calc("hypot(1, 1)").unwrap(), calc("sqrt(2)").unwrap());
+	}
+	#[test]
+	fn int_div() {
+		assert_eq!(calc("7 // 2").unwrap(), num("3"));
+		assert_eq!(calc("(-7) // 2").unwrap(), num("-4"));
+		assert_eq!(calc("7 // -2").unwrap(), num("-4"));
+		assert_eq!(calc("(-7) // -2").unwrap(), num("3"));
+		assert_eq!(calc("7.5 // 2.5").unwrap(), num("3"));
+		assert!(matches!(calc("7 // 0"), Err(CalcError::DivideByZero)));
+	}
+	#[test]
+	fn left_associative() {
+		assert_eq!(calc("12 // 4 // 2").unwrap(), num("1"));
+		assert_eq!(calc("2 * 3 // 4").unwrap(), num("1"));
+		assert_eq!(calc("16 / 4 / 2").unwrap(), num("2"));
+		assert_eq!(calc("17 % 5 % 2").unwrap(), num("0"));
+		assert_eq!(calc("10 - 2 - 3").unwrap(), num("5"));
+	}
+	#[test]
+	fn primes() {
+		assert_eq!(calc("is_prime(0)").unwrap(), num("0"));
+		assert_eq!(calc("is_prime(1)").unwrap(), num("0"));
+		assert_eq!(calc("is_prime(2)").unwrap(), num("1"));
+		assert_eq!(calc("is_prime(97)").unwrap(), num("1"));
+		assert_eq!(calc("is_prime(91)").unwrap(), num("0"));
+		assert_eq!(calc("is_prime(1000000007)").unwrap(), num("1"));
+		assert!(matches!(calc("is_prime(-3)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("is_prime(2.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn abs_bars() {
+		assert_eq!(calc("|-5|").unwrap(), num("5"));
+		assert_eq!(calc("|3 - 7|").unwrap(), num("4"));
+		assert_eq!(calc("x = -3; y = 5; ||x| - |y||").unwrap(), num("2"));
+		assert_eq!(calc("||-1||").unwrap(), num("1"));
+		// A bar after an operand closes, so the middle one of three is a bitwise or
+		assert_eq!(calc("|-2| | |4|").unwrap(), num("6"));
+		assert!(matches!(calc("|1"), Err(CalcError::UnclosedBar)));
+	}
+	#[test]
+	fn one_sided_decimals() {
+		assert_eq!(calc(".5 + .5").unwrap(), num("1"));
+		assert_eq!(calc("5. * 2").unwrap(), num("10"));
+	}
+	#[test]
+	fn op_budget() {
+		assert!(matches!(calc_with("1000!", |context| context.op_budget = Some(100)), Err(CalcError::BudgetExceeded)));
+		assert!(matches!(calc_with("pow(3, 100000)", |context| context.op_budget = Some(100)), Err(CalcError::BudgetExceeded)));
+		assert_eq!(calc_with("1 + 2 * 3", |context| context.op_budget = Some(100)).unwrap(), num("7"));
+		// Unlimited by default
+		assert_eq!(calc("1000! > 0").unwrap(), num("1"));
+	}
+	#[test]
+	fn parse_error_converts() {
+		fn tokens(input: &str) -> CalcResult<Vec<Token>> {
+			Ok(::parser::parse(input)?)
+		}
+		assert!(matches!(tokens("1 @ 2"), Err(CalcError::ParseError(_))));
+		assert!(matches!(calc("1 @ 2"), Err(CalcError::ParseError(_))));
+	}
+	#[test]
+	fn snapshot_and_restore() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		variables.insert("x".to_string(), num("1"));
+		let tokens = ::parser::parse("x = 2; f = ($1); y = 1 / 0").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		let snapshot = context.snapshot();
+		assert!(matches!(calculate_all(&mut context), Err(CalcError::DivideByZero)));
+		assert_eq!(context.variables["x"], num("2"));
+		assert!(context.functions.contains_key("f"));
+
+		context.restore(snapshot);
+		assert_eq!(context.variables.len(), 1);
+		assert_eq!(context.variables["x"], num("1"));
+		assert!(context.functions.is_empty());
+	}
+	#[test]
+	fn statements() {
+		assert_eq!(calc("x = 3; y = 4; x*y").unwrap(), num("12"));
+		// Only assignments gives the last assignment
+		assert_eq!(calc("x = 3; y = 4").unwrap(), num("4"));
+		assert_eq!(calc("x = 3; x;").unwrap(), num("3"));
+		assert_eq!(calc("x = 1; x = x + 1; x * 10").unwrap(), num("20"));
+		assert!(matches!(calc("1;;2"), Err(CalcError::InvalidSyntax(_))));
+	}
+	#[test]
+	fn powmod() {
+		assert_eq!(calc("powmod(2, 10, 1000)").unwrap(), num("24"));
+		assert_eq!(calc("powmod(3, 200, 7)").unwrap(), num("2"));
+		// Fermat's little theorem, with a power far too big to calculate in full
+		assert_eq!(calc("powmod(5, 1000000006, 1000000007)").unwrap(), num("1"));
+		assert!(matches!(calc("powmod(2, 3, 0)"), Err(CalcError::DivideByZero)));
+		assert!(matches!(calc("powmod(2, 3, -5)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("powmod(2.5, 3, 5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn invmod() {
+		assert_eq!(calc("invmod(3, 11)").unwrap(), num("4"));
+		assert_eq!(calc("invmod(-3, 11)").unwrap(), num("7"));
+		assert!(matches!(calc("invmod(2, 4)"), Err(CalcError::NoInverse)));
+		assert!(matches!(calc("invmod(3, 0)"), Err(CalcError::DivideByZero)));
+		assert!(matches!(calc("invmod(1.5, 4)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn euclidean_mod() {
+		assert_eq!(calc("mod(-1, 3)").unwrap(), num("2"));
+		assert_eq!(calc("mod(7, 3)").unwrap(), num("1"));
+		assert_eq!(calc("mod(7, -3)").unwrap(), num("1"));
+		assert_eq!(calc("(-7) % 3").unwrap(), num("2"));
+		assert!(matches!(calc("mod(1, 0)"), Err(CalcError::DivideByZero)));
+		assert!(matches!(calc("mod(1.5, 2)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn trace() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let mut steps = Vec::new();
+		{
+			let tokens = ::parser::parse("2 + 3 * 4").unwrap();
+			let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+			context.trace = Some(&mut steps);
+			assert_eq!(calculate_all(&mut context).unwrap(), num("14"));
+		}
+		assert_eq!(steps, vec![
+			TraceStep { op: Token::Mul, operands: vec![num("3"), num("4")], result: num("12") },
+			TraceStep { op: Token::Add, operands: vec![num("2"), num("12")], result: num("14") }
+		]);
+	}
+	#[test]
+	fn memoize() {
+		let memoize = |context: &mut Context<_>| context.memoize = true;
+		let counted = "c = 0; f = ((c = c + 1) * 0 + $1 * 2); f(3) + f(3); c";
+		assert_eq!(calc_with(counted, memoize).unwrap(), num("1"));
+		assert_eq!(calc(counted).unwrap(), num("2"));
+		// Redefining a function forgets its results
+		assert_eq!(calc_with("f = ($1 * 2); f(3); f = ($1 * 3); f(3)", memoize).unwrap(), num("9"));
+	}
+	#[test]
+	fn builtin_case() {
+		assert_eq!(calc("ABS(-5)").unwrap(), num("5"));
+		assert_eq!(calc("Sqrt(4)").unwrap(), num("2"));
+		// User functions keep their case
+		assert!(matches!(calc("f = ($1); F(1)"), Err(CalcError::UnknownFunction(ref name, _)) if name == "F"));
+	}
+	#[test]
+	fn average() {
+		assert_eq!(calc("avg(1, 2, 3, 4)").unwrap(), num("2.5"));
+		assert_eq!(calc("mean(2, 4)").unwrap(), num("3"));
+		assert!(matches!(calc("avg(1)"), Err(CalcError::IncorrectArguments(2, 1))));
+		assert!(matches!(calc("avg()"), Err(CalcError::IncorrectArguments(2, 0))));
+	}
+	#[test]
+	fn div_scale() {
+		let scale = |context: &mut Context<_>| context.div_scale = 10;
+		assert_eq!(calc_with("10 / 3", scale).unwrap().to_string(), "3.3333333333");
+		assert_eq!(calc_with("sqrt(2)", scale).unwrap().to_string(), "1.4142135623");
+		assert_eq!(calc_with("1 / 4", scale).unwrap(), num("0.25"));
+		assert_eq!(calc("10 / 3").unwrap().to_string().len(), "3.".len() + PRECISION as usize);
+	}
+	#[test]
+	fn bit_counts() {
+		assert_eq!(calc("popcount(7)").unwrap(), num("3"));
+		assert_eq!(calc("popcount(0)").unwrap(), num("0"));
+		assert_eq!(calc("bitlen(8)").unwrap(), num("4"));
+		assert_eq!(calc("bitlen(0)").unwrap(), num("0"));
+		assert!(matches!(calc("popcount(-1)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("bitlen(1.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn rotate() {
+		assert_eq!(calc("rotl(1, 1, 8)").unwrap(), num("2"));
+		assert_eq!(calc("rotr(1, 1, 8)").unwrap(), num("128"));
+		assert_eq!(calc("rotl(128, 1, 8)").unwrap(), num("1"));
+		// Rotating by more than the width wraps around
+		assert_eq!(calc("rotl(1, 9, 8)").unwrap(), num("2"));
+		assert!(matches!(calc("rotl(-1, 1, 8)"), Err(CalcError::NotAPositive)));
+	}
+	#[test]
+	fn recip_and_sq() {
+		assert_eq!(calc("recip(4)").unwrap(), num("0.25"));
+		assert_eq!(calc("sq(-3)").unwrap(), num("9"));
+		assert_eq!(calc_with("recip(3)", |context| context.div_scale = 2).unwrap(), num("0.33"));
+		assert!(matches!(calc("recip(0)"), Err(CalcError::DivideByZero)));
+	}
+	#[test]
+	fn chained_comparisons() {
+		assert_eq!(calc("x = 5; 1 < x < 10").unwrap(), num("1"));
+		assert_eq!(calc("x = 5; 1 < x < 3").unwrap(), num("0"));
+		assert_eq!(calc("1 <= 1 < 2").unwrap(), num("1"));
+		assert_eq!(calc("3 > 2 > 1").unwrap(), num("1"));
+		// Each operand is compared to the next one, not to the result so far
+		assert_eq!(calc("1 < 2 == 1").unwrap(), num("0"));
+		assert_eq!(calc("1 < 3 > 2").unwrap(), num("1"));
+		assert_eq!(calc("2 == 2").unwrap(), num("1"));
+		assert_eq!(calc("2 < 1").unwrap(), num("0"));
+	}
+	#[test]
+	fn protected_names() {
+		let protect = |context: &mut Context<_>| { context.protected.insert("speed".to_string()); };
+		assert!(matches!(calc_with("speed = 3", protect), Err(CalcError::ReadOnly(ref name)) if name == "speed"));
+		assert_eq!(calc_with("x = 3; x", protect).unwrap(), num("3"));
+		// Callers decide, so nothing of their own is protected unless they say so
+		assert_eq!(calc("speed = 3").unwrap(), num("3"));
+	}
+	#[test]
+	fn digits() {
+		assert_eq!(calc("digitsum(123)").unwrap(), num("6"));
+		assert_eq!(calc("digitsum(0)").unwrap(), num("0"));
+		assert_eq!(calc("reverse(123)").unwrap(), num("321"));
+		// Trailing zeros become leading zeros, which disappear
+		assert_eq!(calc("reverse(120)").unwrap(), num("21"));
+		assert!(matches!(calc("digitsum(-1)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("reverse(1.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn scientific_factorial() {
+		assert_eq!(::format::format_scientific(&calc("10!").unwrap(), 3), "3.63e+6");
+	}
+	#[test]
+	fn shift_limit() {
+		assert!(matches!(calc("1 << 10000000000"), Err(CalcError::ShiftTooLarge)));
+		assert!(matches!(calc_with("1 << 65", |context| context.max_shift = 64), Err(CalcError::ShiftTooLarge)));
+		assert_eq!(calc_with("1 << 64", |context| context.max_shift = 64).unwrap(), num("18446744073709551616"));
+		// Shifting right only makes the number smaller
+		assert_eq!(calc("1 >> 10000000000").unwrap(), num("0"));
+	}
+	#[test]
+	fn primitive_errors() {
+		assert!(matches!(calc("1.5 & 2"), Err(CalcError::NotAWhole)));
+		assert!(matches!(calc("~1.5"), Err(CalcError::NotAWhole)));
+		assert!(matches!(calc("1 << 1.5"), Err(CalcError::NotAWhole)));
+		match calc("pow(2, 100) & 1") {
+			Err(CalcError::Overflow { operation, value, target }) => {
+				assert_eq!(operation, "&");
+				assert_eq!(value, "1267650600228229401496703205376");
+				assert_eq!(target, "i64");
+			},
+			other => panic!("expected an overflow, got {:?}", other)
+		}
+	}
+	#[test]
+	fn comments() {
+		assert_eq!(calc("2 + 3 # this is five").unwrap(), num("5"));
+		assert_eq!(calc("x = 2 # two; x * 3").unwrap(), num("6"));
+	}
+	#[test]
+	fn stdlib() {
+		fn calc_stdlib(input: &str, functions: &mut HashMap<String, Vec<Token>>) -> CalcResult<BigDecimal> {
+			let mut variables = HashMap::new();
+			let tokens = ::parser::parse(input).unwrap();
+			let mut context = Context::with_stdlib(tokens.into_iter().peekable(), &mut variables, functions);
+			calculate_all(&mut context)
+		}
+		let mut functions = HashMap::new();
+		assert_eq!(calc_stdlib("double(4) + half(3) + cube(2)", &mut functions).unwrap(), num("17.5"));
+		assert_eq!(calc_stdlib("sign(-5) + lerp(0, 10, 0.5)", &mut functions).unwrap(), num("4"));
+		// Redefining one replaces it
+		assert_eq!(calc_stdlib("double = ($1 * 3); double(2)", &mut functions).unwrap(), num("6"));
+
+		// One that's already defined is kept
+		let mut functions = HashMap::new();
+		functions.insert("half".to_string(), ::parser::parse("$1").unwrap());
+		assert_eq!(calc_stdlib("half(3)", &mut functions).unwrap(), num("3"));
+	}
+	#[test]
+	fn deep_recursion() {
+		// Finite recursion works until `TooDeep`
+		let deep = std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(|| {
+			calc("c = 0; f = ($1 <= 0 || (c = c + 1) && f($1 - 1)); f(50); c").unwrap()
+		}).unwrap();
+		assert_eq!(deep.join().unwrap(), num("50"));
+	}
+	#[test]
+	fn infinite_recursion() {
+		let endless = |input| match calc(input) {
+			Err(CalcError::InfiniteRecursion(name)) => name,
+			other => panic!("{}: {:?}", input, other)
+		};
+		assert_eq!(endless("f = (f($1) + 1); f(1)"), "f");
+		assert_eq!(endless("f = (f($1)); 2 * f(1)"), "f");
+		assert_eq!(endless("f = (g($1)); g = (f($1)); f(1)"), "f");
+		assert_eq!(endless("f = (g()); g = ($1 + f($1)); g(3)"), "f");
+		// Each call differs in its arguments or in a variable, so these aren't caught
+		assert_eq!(calc("f = ($1 <= 1 || f($1 - 1)); f(10)").unwrap(), num("1"));
+		assert_eq!(calc("c = 0; f = (c >= 3 || (c = c + 1) && f($1)); f(1); c").unwrap(), num("3"));
+	}
+	#[test]
+	fn atan2() {
+		assert_eq!(calc("atan2(0, 0)").unwrap(), num("0"));
+		assert!(close("atan2(1, 0)", "pi / 2"));
+		assert!(close("atan2(-1, 0)", "-pi / 2"));
+		assert!(close("atan2(0, 1)", "0"));
+		assert!(close("atan2(0, -1)", "pi"));
+		assert!(close("atan2(1, 1)", "pi / 4"));
+		assert!(close("atan2(1, -1)", "3 * pi / 4"));
+		assert!(close("atan2(-1, -1)", "-3 * pi / 4"));
+		assert!(close("atan2(-1, 1)", "-pi / 4"));
+	}
+	#[test]
+	fn inverse_trig() {
+		assert!(close("asin(1)", "pi / 2"));
+		assert!(close("atan(1)", "pi / 4"));
+		assert!(close("acos(0)", "pi / 2"));
+		assert!(close("acos(1)", "0"));
+		assert!(close("atan(-1000000)", "-atan(1000000)"));
+		assert!(matches!(calc("asin(2)"), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("acos(-1.5)"), Err(CalcError::OutOfDomain(_))));
+	}
+	#[test]
+	fn hyperbolic() {
+		assert_eq!(calc("sinh(0)").unwrap(), num("0"));
+		assert_eq!(calc("cosh(0)").unwrap(), num("1"));
+		assert_eq!(calc("tanh(0)").unwrap(), num("0"));
+		assert!(close("sinh(1)", "(e - 1/e) / 2"));
+		assert!(close("cosh(-1)", "(e + 1/e) / 2"));
+		let tanh = calc("tanh(50)").unwrap();
+		assert!(tanh < num("1") && tanh > num("0.99999999999999999999999999999999999999"));
+	}
+	#[test]
+	fn chained_assignment() {
+		assert_eq!(calc("a = b = 2; a + b").unwrap(), num("4"));
+		// A definition has no value
+		assert_eq!(calc("f = ($1)").unwrap(), num("0"));
+	}
+	#[test]
+	fn variadic_gcd_lcm() {
+		assert_eq!(calc("gcd(12, 18, 24)").unwrap(), num("6"));
+		assert_eq!(calc("gcd(12, 18, 24, 5)").unwrap(), num("1"));
+		assert_eq!(calc("lcm(2, 3, 4)").unwrap(), num("12"));
+		assert_eq!(calc("lcm(2, 3, 4, 5)").unwrap(), num("60"));
+		assert!(matches!(calc("gcd(5)"), Err(CalcError::IncorrectArguments(2, 1))));
+		assert!(matches!(calc("lcm()"), Err(CalcError::IncorrectArguments(2, 0))));
+	}
+	#[test]
+	fn seeded_rand() {
+		let first = calc("rand(42)").unwrap();
+		assert_eq!(calc("rand(42)").unwrap(), first);
+		assert!(first >= num("0") && first < num("1"));
+		assert!(calc("rand(43)").unwrap() != first);
+		for seed in 0..20 {
+			let n = calc(&format!("rand({}, 10)", seed)).unwrap();
+			assert!(n >= num("0") && n < num("10") && n.with_scale(0) == n);
+		}
+		assert!(matches!(calc("rand(1.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn fractional_part() {
+		assert_eq!(calc("frac(3.25)").unwrap(), num("0.25"));
+		assert_eq!(calc("frac(-3.25)").unwrap(), num("-0.25"));
+		assert_eq!(calc("frac(3)").unwrap(), num("0"));
+		assert_eq!(calc("frac(3.000)").unwrap(), num("0"));
+	}
+	#[test]
+	fn primitive_conversions() {
+		assert_eq!(result_to_i64(&num("-42")).unwrap(), -42);
+		assert_eq!(result_to_i64(&num("42.000")).unwrap(), 42);
+		assert!(matches!(result_to_i64(&num("1.5")), Err(CalcError::NotAWhole)));
+		assert!(matches!(result_to_i64(&num("9223372036854775808")), Err(CalcError::Overflow { target: "i64", .. })));
+		assert_eq!(result_to_f64(&num("0.1")).unwrap(), 0.1);
+		assert_eq!(result_to_f64(&num("-2.5")).unwrap(), -2.5);
+		assert!(matches!(result_to_f64(&num("1e400")), Err(CalcError::Overflow { target: "f64", .. })));
+	}
+	#[test]
+	fn epsilon() {
+		assert_eq!(calc("sqrt(2) * sqrt(2) == 2").unwrap(), num("0"));
+		let tolerant = |context: &mut Context<_>| context.epsilon = num("1e-50");
+		assert_eq!(calc_with("sqrt(2) * sqrt(2) == 2", tolerant).unwrap(), num("1"));
+		assert_eq!(calc_with("sqrt(2) * sqrt(2) != 2", tolerant).unwrap(), num("0"));
+		assert_eq!(calc_with("1.1 == 1", tolerant).unwrap(), num("0"));
+	}
+	#[test]
+	fn prime_factor_counts() {
+		assert_eq!(calc("omega(12)").unwrap(), num("2"));
+		assert_eq!(calc("bigomega(12)").unwrap(), num("3"));
+		assert_eq!(calc("omega(13)").unwrap(), num("1"));
+		assert_eq!(calc("bigomega(13)").unwrap(), num("1"));
+		assert_eq!(calc("omega(1)").unwrap(), num("0"));
+		assert_eq!(calc("bigomega(1)").unwrap(), num("0"));
+		assert!(matches!(calc("omega(-4)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("bigomega(2.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn param_prefix() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse_with_prefix("f = (@1 * @2); f(3, 4)", '@').unwrap();
+		let mut context = Context::new(tokens.into_iter().map(|(token, _)| token).peekable(), &mut variables, &mut functions);
+		context.param_prefix = '@';
+		assert_eq!(calculate_all(&mut context).unwrap(), num("12"));
+
+		assert!(::parser::parse_with_prefix("$1", '@').is_err());
+	}
+	#[test]
+	fn precision_argument() {
+		assert_eq!(calc("sqrt(2, 5)").unwrap().to_string(), "1.41421");
+		assert_eq!(calc("sqrt(2, 40)").unwrap().to_string(), "1.4142135623730950488016887242096980785696");
+		assert_eq!(calc("ln(2, 5)").unwrap().to_string(), "0.69314");
+		assert_eq!(calc("sin(1, 5)").unwrap().to_string(), "0.84147");
+		// Without one, it's `div_scale`
+		assert_eq!(calc_with("sqrt(2)", |context| context.div_scale = 5).unwrap().to_string(), "1.41421");
+	}
+	#[test]
+	fn overflow_fields() {
+		match calc("1 & pow(2, 64)") {
+			Err(CalcError::Overflow { ref operation, ref value, target }) => {
+				assert_eq!(operation, "&");
+				assert_eq!(value, "18446744073709551616");
+				assert_eq!(target, "i64");
+			},
+			ref other => panic!("expected an overflow, got {:?}", other)
+		}
+		assert!(matches!(calc("1 << -1"), Err(CalcError::Overflow { target: "usize", .. })));
+		assert_eq!(calc("1 << -1").unwrap_err().to_string(), "-1 in << must fit in the range of an usize primitive");
+	}
+	#[test]
+	fn reduce_fraction() {
+		assert_eq!(reduce(BigInt::from(6), BigInt::from(8)), (BigInt::from(3), BigInt::from(4)));
+		assert_eq!(reduce(BigInt::from(6), BigInt::from(-8)), (BigInt::from(-3), BigInt::from(4)));
+		assert_eq!(reduce(BigInt::from(0), BigInt::from(5)), (BigInt::from(0), BigInt::from(1)));
+		assert_eq!(reduce(BigInt::from(0), BigInt::from(0)), (BigInt::from(0), BigInt::from(0)));
+	}
+	#[test]
+	fn scientific_literals() {
+		assert_eq!(calc("1.5e3").unwrap(), num("1500"));
+		assert_eq!(calc("2E-4 * 10000").unwrap(), num("2"));
+		assert!(matches!(calc("1e"), Err(CalcError::ParseError(ParseError::InvalidNumber(_)))));
+		assert!(matches!(calc("1e+"), Err(CalcError::ParseError(ParseError::InvalidNumber(_)))));
+		assert!(matches!(calc("1e100000000"), Err(CalcError::ParseError(ParseError::InvalidNumber(_)))));
+	}
+	#[test]
+	fn digital_root() {
+		assert_eq!(calc("digroot(9875)").unwrap(), num("2"));
+		assert_eq!(calc("digroot(0)").unwrap(), num("0"));
+		assert_eq!(calc("digroot(9)").unwrap(), num("9"));
+		assert_eq!(calc("digroot(123456789123456789123456789)").unwrap(), num("9"));
+		assert!(matches!(calc("digroot(-1)"), Err(CalcError::NotAPositive)));
+	}
+	#[test]
+	fn forward_references() {
+		assert_eq!(calc("f = (g($1) + 1); g = ($1 * 2); f(3)").unwrap(), num("7"));
+		// Redefining the helper changes the caller too
+		assert_eq!(calc("f = (g($1) + 1); g = ($1 * 2); g = ($1 * 3); f(3)").unwrap(), num("10"));
+		assert!(matches!(calc("f = (g($1)); f(3)"), Err(CalcError::UnknownFunction(ref name, _)) if name == "g"));
+	}
+	#[test]
+	fn distance() {
+		assert_eq!(calc("dist(3, 7)").unwrap(), num("4"));
+		assert_eq!(calc("dist(7, 3)").unwrap(), num("4"));
+		assert_eq!(calc("dist(-1.5, 2)").unwrap(), num("3.5"));
+	}
+	#[test]
+	fn factorial_errors() {
+		assert!(matches!(calc("2.5!"), Err(CalcError::FractionalFactorial)));
+		assert!(matches!(calc("(-3)!"), Err(CalcError::NotAPositive)));
+		assert_eq!(
+			CalcError::FractionalFactorial.to_string(),
+			"You may only take the factorial of whole numbers\nHint: `gamma(x + 1)` is the factorial of a fraction"
+		);
+	}
+	#[test]
+	fn gamma() {
+		assert_eq!(calc("gamma(5)").unwrap(), num("24"));
+		assert_eq!(calc("gamma(1)").unwrap(), num("1"));
+		assert!(close("gamma(0.5)", "sqrt(pi)"));
+		assert!(close("gamma(2.5)", "3 * sqrt(pi) / 4"));
+		assert!(matches!(calc("gamma(0)"), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("gamma(-2)"), Err(CalcError::OutOfDomain(_))));
+	}
+	#[test]
+	fn between() {
+		assert_eq!(calc("between(5, 5, 10)").unwrap(), num("1"));
+		assert_eq!(calc("between(10, 5, 10)").unwrap(), num("1"));
+		assert_eq!(calc("between(11, 5, 10)").unwrap(), num("0"));
+		assert_eq!(calc("between(5.0, 5, 10)").unwrap(), num("1"));
+		// Bounds the wrong way around contain nothing
+		assert_eq!(calc("between(7, 10, 5)").unwrap(), num("0"));
+	}
+	#[test]
+	fn function_source() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse("f = ($1*2+g($1))").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		calculate_all(&mut context).unwrap();
+		assert_eq!(context.function_source("f").unwrap(), "f = ($1 * 2 + g($1))");
+		assert_eq!(context.function_source("g"), None);
+	}
+	#[test]
+	fn max_digits() {
+		let limit = |context: &mut Context<_>| context.max_digits = Some(100);
+		assert!(matches!(calc_with("10000000!", limit), Err(CalcError::ResultTooLarge)));
+		assert!(matches!(calc_with("pow(10, 1000)", limit), Err(CalcError::ResultTooLarge)));
+		assert_eq!(calc_with("pow(10, 99)", limit).unwrap(), calc("pow(10, 99)").unwrap());
+		assert_eq!(calc_with("20!", limit).unwrap(), num("2432902008176640000"));
+	}
+	#[test]
+	fn copysign() {
+		assert_eq!(calc("copysign(3, -1)").unwrap(), num("-3"));
+		assert_eq!(calc("copysign(-3, 2)").unwrap(), num("3"));
+		assert_eq!(calc("copysign(-3, 0)").unwrap(), num("3"));
+		assert_eq!(calc("copysign(-2.5, -0.1)").unwrap(), num("-2.5"));
+	}
+	#[test]
+	fn define_constant() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse("c * 2").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		context.define_constant("c", num("299792458"));
+		let names: Vec<_> = context.constants().into_iter().map(|(name, _)| name).collect();
+		assert_eq!(names, vec!["c", "e", "phi", "pi", "sqrt2", "tau"]);
+		assert_eq!(calculate_all(&mut context).unwrap(), num("599584916"));
+
+		assert!(matches!(calc_with("c = 1", |context| context.define_constant("c", num("1"))), Err(CalcError::ReadOnly(_))));
+	}
+	#[test]
+	fn short_circuit() {
+		assert_eq!(calc("0 && 1/0").unwrap(), num("0"));
+		assert_eq!(calc("1 || 1/0").unwrap(), num("1"));
+		assert!(matches!(calc("1 && 1/0"), Err(CalcError::DivideByZero)));
+		assert!(matches!(calc("0 || 1/0"), Err(CalcError::DivideByZero)));
+		// The side that isn't calculated doesn't assign either
+		assert_eq!(calc("x = 1; 0 && (x = 2); x").unwrap(), num("1"));
+		assert_eq!(calc("2 && 3").unwrap(), num("1"));
+		assert_eq!(calc("5 || 0").unwrap(), num("1"));
+		assert_eq!(calc("0 || 0").unwrap(), num("0"));
+	}
+	#[test]
+	fn logical_not() {
+		assert_eq!(calc("!0").unwrap(), num("1"));
+		assert_eq!(calc("!5").unwrap(), num("0"));
+		assert_eq!(calc("!!5").unwrap(), num("1"));
+		assert_eq!(calc("!0.5").unwrap(), num("0"));
+		// Bitwise stays `~`
+		assert_eq!(calc("~5").unwrap(), num("-6"));
+		assert_eq!(calc("~~5").unwrap(), num("5"));
+	}
+	#[test]
+	fn classify_results() {
+		use format::{classify, ResultKind};
+		assert_eq!(classify(&calc("5!").unwrap()), ResultKind::Integer);
+		assert_eq!(classify(&calc("1/3").unwrap()), ResultKind::Decimal);
+		assert_eq!(classify(&calc("2.5 * 2").unwrap()), ResultKind::Integer);
+	}
+	#[test]
+	fn fractional_pow() {
+		assert!(close("pow(8, 1/3)", "2"));
+		assert!(close("pow(9, 0.5)", "3"));
+		assert!(close("pow(2, 0.5)", "sqrt(2)"));
+		assert!(close("pow(4, -0.5)", "0.5"));
+		// Whole powers stay exact
+		assert_eq!(calc("pow(2, 100)").unwrap(), num("1267650600228229401496703205376"));
+		assert!(matches!(calc("pow(-8, 0.5)"), Err(CalcError::OutOfDomain(_))));
+	}
+	#[test]
+	fn last_error() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse("1 / 0").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		assert!(context.last_error().is_none());
+		assert!(calculate(&mut context).is_err());
+		assert!(matches!(context.last_error(), Some(&CalcError::DivideByZero)));
+
+		context.tokens = vec![Token::Num(num("1"))].into_iter().peekable();
+		assert_eq!(calculate(&mut context).unwrap(), num("1"));
+		assert!(context.last_error().is_none());
+	}
+	#[test]
+	fn integer_log() {
+		assert_eq!(calc("ilog(2, 1000)").unwrap(), num("9"));
+		assert_eq!(calc("ilog(2, 1023)").unwrap(), num("9"));
+		assert_eq!(calc("ilog(2, 1024)").unwrap(), num("10"));
+		assert_eq!(calc("ilog(10, 999)").unwrap(), num("2"));
+		assert_eq!(calc("ilog(10, 1000)").unwrap(), num("3"));
+		assert_eq!(calc("ilog(3, 3486784400)").unwrap(), num("19"));
+		assert_eq!(calc("ilog(3, 3486784401)").unwrap(), num("20"));
+		assert!(matches!(calc("ilog(1, 5)"), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("ilog(2, 0)"), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("ilog(2, 2.5)"), Err(CalcError::NotAWhole)));
+	}
+	#[test]
+	fn reserved_names() {
+		assert!(matches!(calc("abs = (0)"), Err(CalcError::ReservedName(ref name)) if name == "abs"));
+		assert!(matches!(calc("POW = ($1)"), Err(CalcError::ReservedName(_))));
+		assert!(BUILTINS.contains(&"sqrt"));
+		// Variables don't share a namespace with functions
+		assert_eq!(calc("abs = 3; abs(-abs)").unwrap(), num("3"));
+	}
+	#[test]
+	fn pow_cache() {
+		let input = "pow(2, 10) + pow(2, 10) + pow(3, 5) + pow(5, 3) + pow(7, 2)";
+		assert_eq!(calc_with(input, |context| context.pow_cache_size = 2).unwrap(), calc(input).unwrap());
+
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse(input).unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		context.pow_cache_size = 2;
+		calculate_all(&mut context).unwrap();
+		assert!(context.pow_cache.len() <= 2);
+
+		// Off by default
+		let tokens = ::parser::parse(input).unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		calculate_all(&mut context).unwrap();
+		assert!(context.pow_cache.is_empty());
+	}
+	#[test]
+	fn trig_reduction() {
+		assert!(close("sin(1000000)", "sin(1000000 + tau)"));
+		assert!(close("cos(-1000000)", "cos(-1000000 - 2 * tau)"));
+		assert!(close("sin(pi / 6)", "0.5"));
+		assert!(close("cos(tau)", "1"));
+	}
+	#[test]
+	fn round_mode() {
+		let half_even = |context: &mut Context<_>| context.round_mode = RoundMode::HalfEven;
+		assert_eq!(calc("round(2.5)").unwrap(), num("3"));
+		assert_eq!(calc("round(-2.5)").unwrap(), num("-3"));
+		assert_eq!(calc_with("round(2.5)", half_even).unwrap(), num("2"));
+		assert_eq!(calc_with("round(3.5)", half_even).unwrap(), num("4"));
+		assert_eq!(calc_with("round(2.51)", half_even).unwrap(), num("3"));
+		// Divisions round by the mode too
+		assert_eq!(calc_with("0.25 / 10", |context| { context.div_scale = 2; context.round_mode = RoundMode::HalfEven; }).unwrap(), num("0.02"));
+		assert_eq!(calc_with("0.25 / 10", |context| context.div_scale = 2).unwrap(), num("0.03"));
+	}
+	#[test]
+	fn empty_variadics() {
+		assert!(matches!(calc("max()"), Err(CalcError::IncorrectArguments(1, 0))));
+		assert!(matches!(calc("min()"), Err(CalcError::IncorrectArguments(1, 0))));
+		assert_eq!(calc("max(3)").unwrap(), num("3"));
+		assert_eq!(calc("min(3, -1, 2)").unwrap(), num("-1"));
+	}
+	#[test]
+	fn const_statement() {
+		assert!(matches!(calc("const k = 5; k = 6"), Err(CalcError::ReadOnly(ref name)) if name == "k"));
+		assert_eq!(calc("const k = 5; k * 2").unwrap(), num("10"));
+		assert_eq!(calc("const k = 2 + 3; k").unwrap(), num("5"));
+		assert!(matches!(calc("const k = 1; const k = 2"), Err(CalcError::ReadOnly(_))));
+		assert!(matches!(calc("const pi = 3"), Err(CalcError::ReadOnly(_))));
+	}
+	#[test]
+	fn decompose() {
+		for input in &["123.45", "1200", "-0.5", "0", "2.500", "7"] {
+			let recombined = calc(&format!("mantissa({0}) * pow(10, exponent10({0}))", input)).unwrap();
+			assert_eq!(recombined, num(input), "{}", input);
+		}
+		assert_eq!(calc("mantissa(1200)").unwrap(), num("12"));
+		assert_eq!(calc("exponent10(1200)").unwrap(), num("2"));
+		assert_eq!(calc("mantissa(123.45)").unwrap(), num("12345"));
+		assert_eq!(calc("exponent10(123.45)").unwrap(), num("-2"));
+	}
+	#[test]
+	fn at_level() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse("1 + 2, 3 * 4)").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		assert_eq!(calculate_at_level(&mut context, 1).unwrap(), num("3"));
+		assert_eq!(context.next(), Some(Token::Separator));
+		assert_eq!(calculate_at_level(&mut context, 1).unwrap(), num("12"));
+		assert_eq!(context.next(), Some(Token::ParenClose));
+
+		// At level 0 the separator is an error
+		assert!(matches!(calc("1 + 2, 3"), Err(CalcError::ExpectedEOF(Token::Separator, _))));
+	}
+	#[test]
+	fn sum_range_limits() {
+		assert!(matches!(calc("sum_range(i, 1.5, 3, i)"), Err(CalcError::NotAWhole)));
+		assert!(matches!(calc("sum_range(i, 1, 2.5, i)"), Err(CalcError::NotAWhole)));
+		assert!(matches!(calc("sum_range(2, 1, 3, 1)"), Err(CalcError::InvalidSyntax(_))));
+		let budget = |context: &mut Context<_>| context.op_budget = Some(1000);
+		assert!(matches!(calc_with("sum_range(i, 1, 1000000000, i)", budget), Err(CalcError::BudgetExceeded)));
+	}
+	#[test]
+	fn zero_powers() {
+		assert_eq!(calc("pow(0, 0)").unwrap(), num("1"));
+		assert_eq!(calc("pow(0, 3)").unwrap(), num("0"));
+		assert!(matches!(calc("pow(0, -1)"), Err(CalcError::DivideByZero)));
+		assert!(matches!(calc("pow(0, -0.5)"), Err(CalcError::DivideByZero)));
+	}
+	#[test]
+	fn exact_sqrt() {
+		let root = calc("sqrt(144)").unwrap();
+		assert_eq!(root, num("12"));
+		assert_eq!(root.as_bigint_and_exponent().1, 0);
+		assert_eq!(calc("sqrt(0)").unwrap(), num("0"));
+		assert_eq!(calc("sqrt(100000000000000000000000000000000000000)").unwrap().to_string(), "10000000000000000000");
+		assert_eq!(calc("sqrt(2.25)").unwrap(), num("1.5"));
+		let root = calc("sqrt(15)").unwrap();
+		assert!(root > num("3.87298334620741") && root < num("3.87298334620742"));
+	}
+	#[test]
+	fn read_only() {
+		let read_only = |context: &mut Context<_>| {
+			context.variables.insert("x".to_string(), num("4"));
+			context.functions.insert("f".to_string(), ::parser::parse("$1 * 2").unwrap());
+			context.read_only = true;
+		};
+		assert_eq!(calc_with("x + 1", read_only).unwrap(), num("5"));
+		assert_eq!(calc_with("f(x)", read_only).unwrap(), num("8"));
+		assert!(matches!(calc_with("x = 5", read_only), Err(CalcError::AssignmentNotAllowed)));
+		assert!(matches!(calc_with("g = ($1)", read_only), Err(CalcError::AssignmentNotAllowed)));
+		assert!(matches!(calc_with("const k = 1", read_only), Err(CalcError::AssignmentNotAllowed)));
+	}
+	#[test]
+	fn angles() {
+		assert!(close("todeg(pi)", "180"));
+		assert!(close("torad(90)", "pi / 2"));
+		assert!(close("normalize_angle(3 * pi)", "pi"));
+		assert!(close("normalize_angle(-pi / 2)", "3 * pi / 2"));
+		assert!(close("normalize_angle(1000 * tau + 1)", "1"));
+		let wrapped = calc("normalize_angle(-100)").unwrap();
+		assert!(wrapped >= num("0") && wrapped < calc("tau").unwrap());
+		assert!(close("normalize_angle(-100)", "-100 + 16 * tau"));
+	}
+	#[test]
+	fn history() {
+		use std::collections::VecDeque;
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let mut history = VecDeque::new();
+		for &(input, expected) in &[("10", "10"), ("x = 5", "5"), ("20", "20"), ("ans(1) + ans(2)", "30"), ("ans(1)", "30")] {
+			let tokens = ::parser::parse(input).unwrap();
+			let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+			context.history = Some(&mut history);
+			context.history_size = 3;
+			assert_eq!(calculate_all(&mut context).unwrap(), num(expected));
+		}
+		// Assignments aren't remembered, and only the last `history_size` are kept
+		assert_eq!(history, vec![num("30"), num("30"), num("20")]);
+
+		let tokens = ::parser::parse("ans(4)").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		context.history = Some(&mut history);
+		assert!(matches!(calculate_all(&mut context), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("ans(0)"), Err(CalcError::OutOfDomain(_))));
+		assert!(matches!(calc("ans(1)"), Err(CalcError::OutOfDomain(_))));
+	}
+	#[test]
+	fn error_categories() {
+		assert_eq!(calc("(1").unwrap_err().category(), ErrorCategory::Syntax);
+		assert_eq!(calc("1 @ 2").unwrap_err().category(), ErrorCategory::Syntax);
+		assert_eq!(calc("1 / 0").unwrap_err().category(), ErrorCategory::Domain);
+		assert_eq!(calc("1.5 & 1").unwrap_err().category(), ErrorCategory::Domain);
+		assert_eq!(calc("1 << 10000000000").unwrap_err().category(), ErrorCategory::Resource);
+		assert_eq!(calc_with("10!", |context| context.op_budget = Some(1)).unwrap_err().category(), ErrorCategory::Resource);
+		assert_eq!(calc("x").unwrap_err().category(), ErrorCategory::Unknown);
+		assert_eq!(calc("pi = 3").unwrap_err().category(), ErrorCategory::Unknown);
+	}
+	#[test]
+	fn isolated_functions() {
+		let isolate = |context: &mut Context<_>| context.isolate_functions = true;
+		assert!(matches!(calc_with("f = (y = $1 * 2); f(3); y", isolate), Err(CalcError::UnknownVariable(ref name, _)) if name == "y"));
+		assert_eq!(calc_with("f = (y = $1 * 2); f(3)", isolate).unwrap(), num("6"));
+		// Functions still see the outer variables
+		assert_eq!(calc_with("x = 4; f = ($1 + x); f(1)", isolate).unwrap(), num("5"));
+		// Leaking is the default
+		assert_eq!(calc("f = (y = $1 * 2); f(3); y").unwrap(), num("6"));
+	}
+	#[test]
+	fn sqrt_extremes() {
+		// Far from 1 both ways, and still well within `MAX_SQRT_ITERATIONS`
+		let root = calc("sqrt(pow(10, 200) + 1)").unwrap();
+		assert_eq!(root.with_scale(0), calc("pow(10, 100)").unwrap());
+		assert_eq!(calc("sqrt(1e-100)").unwrap(), num("1e-50"));
+		assert_eq!(calc("isqrt(pow(2, 1000))").unwrap(), calc("pow(2, 500)").unwrap());
+	}
+	#[test]
+	fn operator_table() {
+		let mul = operator_info(&Token::Mul).unwrap();
+		let add = operator_info(&Token::Add).unwrap();
+		assert!(mul.precedence > add.precedence);
+		assert_eq!(mul.associativity, Associativity::Left);
+		assert_eq!(operator_info(&Token::Sub).unwrap().associativity, Associativity::Left);
+		assert_eq!(operator_info(&Token::Less).unwrap().associativity, Associativity::Chained);
+		assert!(operator_info(&Token::Factorial).unwrap().unary);
+		assert!(operator_info(&Token::LogicalAnd).unwrap().precedence > operator_info(&Token::LogicalOr).unwrap().precedence);
+		assert!(operator_info(&Token::Num(num("1"))).is_none());
+		assert!(operator_info(&Token::ParenOpen).is_none());
+
+		// The table agrees with how it's calculated
+		assert_eq!(calc("1 + 2 * 3").unwrap(), num("7"));
+		assert_eq!(calc("10 - 2 - 3").unwrap(), num("5"));
+	}
+	#[test]
+	fn double_factorial() {
+		assert_eq!(calc("dfact(7)").unwrap(), num("105"));
+		assert_eq!(calc("dfact(8)").unwrap(), num("384"));
+		assert_eq!(calc("dfact(0)").unwrap(), num("1"));
+		assert_eq!(calc("dfact(1)").unwrap(), num("1"));
+		assert!(matches!(calc("dfact(-1)"), Err(CalcError::NotAPositive)));
+		assert!(matches!(calc("dfact(2.5)"), Err(CalcError::FractionalFactorial)));
+	}
+	#[test]
+	fn truncation_warning() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let tokens = ::parser::parse("1 / 3 + sqrt(4) + 1 / 4").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		context.div_scale = 5;
+		let calculated = calculate_verbose(&mut context).unwrap();
+		assert_eq!(calculated.value, num("2.58333"));
+		assert_eq!(calculated.warnings, vec![CalcWarning::Truncated(5)]);
+		assert_eq!(calculated.warnings[0].to_string(), "The result was cut off after 5 decimals");
+
+		// Exact results warn about nothing
+		let tokens = ::parser::parse("1 / 4 + sqrt(4)").unwrap();
+		let mut context = Context::new(tokens.into_iter().peekable(), &mut variables, &mut functions);
+		assert!(calculate_verbose(&mut context).unwrap().warnings.is_empty());
+	}
+	#[test]
+	fn in_range() {
+		// in_range(x, lo, hi, lo_inclusive, hi_inclusive)
+		assert_eq!(calc("in_range(5, 5, 10, 1, 1)").unwrap(), num("1"));
+		assert_eq!(calc("in_range(10, 5, 10, 1, 1)").unwrap(), num("1"));
+		assert_eq!(calc("in_range(5, 5, 10, 0, 1)").unwrap(), num("0"));
+		assert_eq!(calc("in_range(10, 5, 10, 0, 1)").unwrap(), num("1"));
+		assert_eq!(calc("in_range(5, 5, 10, 1, 0)").unwrap(), num("1"));
+		assert_eq!(calc("in_range(10, 5, 10, 1, 0)").unwrap(), num("0"));
+		assert_eq!(calc("in_range(5, 5, 10, 0, 0)").unwrap(), num("0"));
+		assert_eq!(calc("in_range(10, 5, 10, 0, 0)").unwrap(), num("0"));
+		assert_eq!(calc("in_range(7, 5, 10, 0, 0)").unwrap(), num("1"));
+		assert!(matches!(calc("in_range(7, 5, 10)"), Err(CalcError::IncorrectArguments(5, 3))));
+	}
+	#[test]
+	fn pct_change() {
+		assert_eq!(calc("pct_change(50, 75)").unwrap(), num("50"));
+		assert_eq!(calc("pct_change(80, 60)").unwrap(), num("-25"));
+		assert_eq!(calc_with("pct_change(3, 4)", |context| context.div_scale = 3).unwrap(), num("33.333"));
+		assert!(matches!(calc("pct_change(0, 5)"), Err(CalcError::DivideByZero)));
+	}
+	#[test]
+	fn multiline() {
+		assert_eq!(calc("x = 3\nx * 2").unwrap(), num("6"));
+		assert_eq!(calc("f = (1 +\n2); f()").unwrap(), num("3"));
+		assert_eq!(calc("x = 1 # one\nx + 1").unwrap(), num("2"));
+	}
+}
@@ -0,0 +1,157 @@
+use bigdecimal::BigDecimal;
+use std::cmp;
+
+/// Whether a number is whole or not, see `classify`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResultKind {
+	Integer,
+	Decimal
+}
+
+/// Tells whether `value` is a whole number, no matter how many zeros it has after the decimal point:
+/// `120.00` is an `Integer`, but `0.5` is a `Decimal`
+pub fn classify(value: &BigDecimal) -> ResultKind {
+	if value.with_scale(0) == *value {
+		ResultKind::Integer
+	} else {
+		ResultKind::Decimal
+	}
+}
+/// Writes `value` without a decimal point if it's whole, like `120` for `120.00`.
+/// Anything else is written as it is.
+pub fn format_result_smart(value: &BigDecimal) -> String {
+	match classify(value) {
+		ResultKind::Integer => value.with_scale(0).to_string(),
+		ResultKind::Decimal => value.to_string()
+	}
+}
+/// Removes the zeros at the end of the decimals, like `2.5` for `2.500`, without changing the value.
+/// Zeros before the decimal point stay, so `100` is still `100`.
+pub fn normalize_output(value: &BigDecimal) -> BigDecimal {
+	use num::Zero;
+
+	let (int, scale) = value.as_bigint_and_exponent();
+	if int.is_zero() {
+		return BigDecimal::zero();
+	}
+	let digits = int.to_str_radix(10);
+	let zeros = digits.len() - digits.trim_end_matches('0').len();
+	value.with_scale(scale - cmp::min(zeros as i64, cmp::max(scale, 0)))
+}
+/// Writes `value` with `sep` between every group of three digits in the whole part, like `1,234,567.89`.
+/// The decimals are left as they are.
+pub fn format_grouped(value: &BigDecimal, sep: char) -> String {
+	let string = value.to_string();
+	let (sign, digits) = if string.starts_with('-') {
+		string.split_at(1)
+	} else {
+		("", &*string)
+	};
+	let (whole, decimals) = match digits.find('.') {
+		Some(i) => digits.split_at(i),
+		None => (digits, "")
+	};
+
+	let mut output = String::with_capacity(string.len() + whole.len() / 3);
+	output.push_str(sign);
+	for (i, c) in whole.chars().enumerate() {
+		if i != 0 && (whole.len() - i) % 3 == 0 {
+			output.push(sep);
+		}
+		output.push(c);
+	}
+	output.push_str(decimals);
+	output
+}
+/// Writes `value` in scientific notation rounded to `sig_figs` significant figures (at least 1),
+/// like `1.23e+6`. The exponent always has a sign, and zero is written `0.00e+0`.
+/// Rounding is half away from zero.
+pub fn format_scientific(value: &BigDecimal, sig_figs: usize) -> String {
+	use num::{BigInt, Signed, Zero};
+
+	let sig_figs = cmp::max(sig_figs, 1);
+	let (int, scale) = value.as_bigint_and_exponent();
+
+	let mut output = String::new();
+	if int.is_negative() {
+		output.push('-');
+	}
+
+	let (mut digits, mut exponent) = if int.is_zero() {
+		(String::from("0"), 0)
+	} else {
+		let digits = int.abs().to_str_radix(10);
+		let exponent = digits.len() as i64 - 1 - scale;
+		(digits, exponent)
+	};
+
+	if digits.len() > sig_figs {
+		let round_up = digits.as_bytes()[sig_figs] >= b'5';
+		digits.truncate(sig_figs);
+		if round_up {
+			digits = (digits.parse::<BigInt>().unwrap() + BigInt::from(1)).to_str_radix(10);
+			// 999 rounded up is 1000, which is one more digit
+			if digits.len() > sig_figs {
+				digits.truncate(sig_figs);
+				exponent += 1;
+			}
+		}
+	}
+	while digits.len() < sig_figs {
+		digits.push('0');
+	}
+
+	output.push_str(&digits[..1]);
+	if sig_figs > 1 {
+		output.push('.');
+		output.push_str(&digits[1..]);
+	}
+	output.push('e');
+	output.push(if exponent < 0 { '-' } else { '+' });
+	output.push_str(&exponent.abs().to_string());
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn num(num: &str) -> BigDecimal {
+		num.parse().unwrap()
+	}
+
+	#[test]
+	fn grouped() {
+		assert_eq!(format_grouped(&num("1234567.89"), ','), "1,234,567.89");
+		assert_eq!(format_grouped(&num("1234567"), ','), "1,234,567");
+		assert_eq!(format_grouped(&num("-1234.5678"), ' '), "-1 234.5678");
+		assert_eq!(format_grouped(&num("-123"), ','), "-123");
+		assert_eq!(format_grouped(&num("999.99"), ','), "999.99");
+		assert_eq!(format_grouped(&num("100000"), ','), "100,000");
+	}
+	#[test]
+	fn scientific() {
+		assert_eq!(format_scientific(&num("3628800"), 3), "3.63e+6");
+		assert_eq!(format_scientific(&num("-0.00012345"), 3), "-1.23e-4");
+		assert_eq!(format_scientific(&num("9.995"), 3), "1.00e+1");
+		assert_eq!(format_scientific(&num("0"), 3), "0.00e+0");
+		assert_eq!(format_scientific(&num("5"), 1), "5e+0");
+		assert_eq!(format_scientific(&num("12"), 0), "1e+1");
+	}
+	#[test]
+	fn smart() {
+		assert_eq!(classify(&num("120")), ResultKind::Integer);
+		assert_eq!(classify(&num("120.00")), ResultKind::Integer);
+		assert_eq!(classify(&num("0.5")), ResultKind::Decimal);
+		assert_eq!(format_result_smart(&num("120.00")), "120");
+		assert_eq!(format_result_smart(&num("-2.50")), "-2.50");
+	}
+	#[test]
+	fn normalized() {
+		assert_eq!(normalize_output(&num("2.500")).to_string(), "2.5");
+		assert_eq!(normalize_output(&num("100")).to_string(), "100");
+		assert_eq!(normalize_output(&num("100.00")).to_string(), "100");
+		assert_eq!(normalize_output(&num("-0.0100")).to_string(), "-0.01");
+		assert_eq!(normalize_output(&num("0.000")).to_string(), "0");
+	}
+}
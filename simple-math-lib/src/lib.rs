@@ -1,25 +1,88 @@
 extern crate bigdecimal;
 extern crate num;
+#[cfg(feature = "serialize")] extern crate serde;
+#[cfg(feature = "serialize")] #[macro_use] extern crate serde_derive;
+#[cfg(feature = "serialize")] extern crate serde_json;
 
+pub mod ast;
 pub mod calculator;
+pub mod format;
 pub mod parser;
+#[cfg(feature = "serialize")] pub mod state;
 
 use bigdecimal::BigDecimal;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 
-/// Calls both parser::parse and calculator::calculate
+/// Calls both parser::parse_with_positions and calculator::calculate_all
 /// and merges the output into one happy Result.
 pub fn parse_and_calc(
 		input: &str,
 		variables: &mut HashMap<String, BigDecimal>,
 		functions: &mut HashMap<String, Vec<parser::Token>>
-	) -> Result<BigDecimal, calculator::CalcError> {
-
-	parser::parse(input).map_err(|err| err.into()).and_then(|parsed| {
-		calculator::calculate(&mut calculator::Context::new(
-			parsed.into_iter().peekable(),
-			variables,
-			functions
-		))
-	})
+	) -> calculator::CalcResult<BigDecimal> {
+
+	parse_and_calc_protected(input, variables, functions, &mut HashSet::new())
+}
+/// Same as `parse_and_calc`, but the names in `protected` are read-only too,
+/// and the ones that become read-only (like with `const k = 5`) are added to it.
+/// Keeping it along with the variables keeps those names read-only for the next input.
+fn parse_and_calc_protected(
+		input: &str,
+		variables: &mut HashMap<String, BigDecimal>,
+		functions: &mut HashMap<String, Vec<parser::Token>>,
+		protected: &mut HashSet<String>
+	) -> calculator::CalcResult<BigDecimal> {
+
+	let (tokens, mut positions): (Vec<_>, Vec<_>) = parser::parse_with_positions(input)?.into_iter().unzip();
+	positions.push(input.len());
+
+	let mut context = calculator::Context::new(tokens.into_iter().peekable(), variables, functions);
+	context.positions = positions;
+	context.line_starts = parser::line_starts(input);
+	context.protected.extend(protected.drain());
+	let result = calculator::calculate_all(&mut context);
+	*protected = mem::take(&mut context.protected);
+	result
+}
+
+/// Calls `parse_and_calc` on each input in order, all sharing the same variables and functions,
+/// so later inputs can use what earlier ones assigned.
+/// A `const` in one input stays read-only in the later ones.
+/// An input failing doesn't stop the rest, each one gets its own result.
+pub fn eval_batch(
+		inputs: &[&str],
+		variables: &mut HashMap<String, BigDecimal>,
+		functions: &mut HashMap<String, Vec<parser::Token>>
+	) -> Vec<calculator::CalcResult<BigDecimal>> {
+
+	let mut protected = HashSet::new();
+	inputs.iter().map(|input| parse_and_calc_protected(input, variables, functions, &mut protected)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn const_stays_read_only_in_batch() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let results = eval_batch(&["const k = 5", "k = 6", "k * 2"], &mut variables, &mut functions);
+		assert_eq!(results[0].as_ref().unwrap(), &BigDecimal::from(5));
+		assert!(matches!(results[1], Err(calculator::CalcError::ReadOnly(_))));
+		assert_eq!(results[2].as_ref().unwrap(), &BigDecimal::from(10));
+	}
+	#[test]
+	fn batch_shares_variables() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		let results = eval_batch(&["x = 4", "x * 2", "1 / 0", "f = ($1 + x)", "f(1)"], &mut variables, &mut functions);
+		assert_eq!(results.len(), 5);
+		assert_eq!(results[1].as_ref().unwrap(), &BigDecimal::from(8));
+		// One failing doesn't stop the rest
+		assert!(matches!(results[2], Err(calculator::CalcError::DivideByZero)));
+		assert_eq!(results[4].as_ref().unwrap(), &BigDecimal::from(5));
+		assert_eq!(variables["x"], BigDecimal::from(4));
+	}
 }
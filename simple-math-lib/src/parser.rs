@@ -1,21 +1,31 @@
 use bigdecimal::BigDecimal;
-use calculator::CalcError;
 use std::{self, fmt, mem};
 
+/// The character function parameters start with, like `$1`, unless another one is given to `parse_with_prefix`
+pub const DEFAULT_PREFIX: char = '$';
+/// How large the exponent of a number in scientific notation may be, either way.
+/// `1e10000` already has ten thousand digits, and every digit takes memory.
+pub const MAX_EXPONENT: i64 = 10_000;
+
 /// A token
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum Token {
 	BlockName(String),
 	ParenOpen,
 	Separator,
+	Semicolon,
 	ParenClose,
+	Bar,
 	VarAssign(String),
 	VarGet(String),
-	Num(BigDecimal),
+	Const,
+	Num(#[cfg_attr(feature = "serialize", serde(with = "::state::decimal"))] BigDecimal),
 	Add,
 	Sub,
 	Mul,
 	Div,
+	IntDiv,
 	Mod,
 	And,
 	Or,
@@ -23,7 +33,16 @@ pub enum Token {
 	BitshiftLeft,
 	BitshiftRight,
 	Not,
-	Factorial
+	Factorial,
+	Equal,
+	NotEqual,
+	Less,
+	LessEqual,
+	Greater,
+	GreaterEqual,
+	LogicalAnd,
+	LogicalOr,
+	LogicalNot
 }
 
 impl fmt::Display for Token {
@@ -32,14 +51,18 @@ impl fmt::Display for Token {
 			Token::BlockName(ref name) => write!(f, "\"{}\"", name),
 			Token::ParenOpen => write!(f, "("),
 			Token::Separator => write!(f, ","),
+			Token::Semicolon => write!(f, ";"),
 			Token::ParenClose => write!(f, ")"),
+			Token::Bar => write!(f, "Absolute value bar (|)"),
 			Token::VarAssign(ref name) => write!(f, "Variable assignment \"{}\"", name),
 			Token::VarGet(ref name) => write!(f, "Variable \"{}\"", name),
+			Token::Const => write!(f, "Constant declaration (const)"),
 			Token::Num(ref num) => write!(f, "Number {}", num),
 			Token::Add => write!(f, "Plus (+)"),
 			Token::Sub => write!(f, "Minus (-)"),
 			Token::Mul => write!(f, "Times (*)"),
 			Token::Div => write!(f, "Division symbol (/)"),
+			Token::IntDiv => write!(f, "Floor division (//)"),
 			Token::Mod => write!(f, "Modulus (%)"),
 			Token::And => write!(f, "Bitwise AND (&)"),
 			Token::Or => write!(f, "Bitwise OR (|)"),
@@ -47,18 +70,27 @@ impl fmt::Display for Token {
 			Token::BitshiftLeft => write!(f, "Bitshift left (<<)"),
 			Token::BitshiftRight => write!(f, "Bitshift right (>>)"),
 			Token::Not => write!(f, "Bitwise NOT (~)"),
-			Token::Factorial => write!(f, "Factorial (!)")
+			Token::Factorial => write!(f, "Factorial (!)"),
+			Token::Equal => write!(f, "Equal to (==)"),
+			Token::NotEqual => write!(f, "Not equal to (!=)"),
+			Token::Less => write!(f, "Less than (<)"),
+			Token::LessEqual => write!(f, "Less than or equal to (<=)"),
+			Token::Greater => write!(f, "Greater than (>)"),
+			Token::GreaterEqual => write!(f, "Greater than or equal to (>=)"),
+			Token::LogicalAnd => write!(f, "Logical AND (&&)"),
+			Token::LogicalOr => write!(f, "Logical OR (||)"),
+			Token::LogicalNot => write!(f, "Logical NOT (!)")
 		}
 	}
 }
 
 /// An error when parsing
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ParseError {
 	DisallowedChar(char),
 	DisallowedDecimal,
 	DisallowedVariable(String),
-	UnclosedBitShift(char)
+	InvalidNumber(String)
 }
 impl fmt::Display for ParseError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -66,9 +98,8 @@ impl fmt::Display for ParseError {
 		match *self {
 			ParseError::DisallowedChar(c) => write!(f, "Character '{}' neither a number nor a valid letter \
 														in a function or variable name.", c),
-			ParseError::UnclosedBitShift(c) => write!(f, "Character '{}' isn't followed by another '{}'.\n\
-														  Looks like a failed attempt to bitshift.", c, c),
 			ParseError::DisallowedVariable(ref var) => write!(f, "\"{}\" is not a valid variable name.", var),
+			ParseError::InvalidNumber(ref num) => write!(f, "\"{}\" is not a valid number.", num),
 			_ => write!(f, "{}", self.description())
 		}
 	}
@@ -79,118 +110,317 @@ impl std::error::Error for ParseError {
 			ParseError::DisallowedChar(_) => "A character you used was not allowed",
 			ParseError::DisallowedDecimal => "You may only use whole numbers in this context",
 			ParseError::DisallowedVariable(_) => "Not a valid variable name.",
-			ParseError::UnclosedBitShift(_) => "A < or > wasn't followed by another one, which is the way to bitshift"
+			ParseError::InvalidNumber(_) => "Not a valid number."
 		}
 	}
 }
-impl Into<CalcError> for ParseError {
-	fn into(self) -> CalcError {
-		CalcError::ParseError(self)
-	}
-}
 
 /// "Parse" the string into a list of tokens.
 /// This is technically actually a tokenizer...
+///
+/// A number directly followed by a parenthesis, a variable or a function call
+/// gets an implicit multiplication inserted (`2(3)`, `2x`, `2abs(x)`).
+/// Only numbers do this: an identifier followed by a parenthesis is always
+/// a function call, since `x(3)` could just as well mean calling `x`.
+/// That's the ambiguity the `UnknownFunction` hint refers to.
+///
+/// A `|` where an operand is expected opens an absolute value bar, a `|` after an operand
+/// closes the innermost open bar, and only when no bar is open (inside the current parenthesis)
+/// it's a bitwise OR.
+/// So `|a| | |b|` is `abs(a) | abs(b)`, and a bitwise OR inside bars needs parenthesis: `|(a | b)|`.
+/// The same goes for `||`, which is only a logical OR where a bitwise OR could be.
+///
+/// A `!` after an operand is a factorial (`x!`), and before one it's a logical NOT (`!x`).
+///
+/// A `#` starts a comment, which is skipped up to the next `;`, line break or the end of the input.
+///
+/// A line break ends the statement like a `;`, so `x = 3` and `x * 2` on two lines is the same as `x = 3; x * 2`.
+/// It only does outside of parenthesis, and only after an operand, so a line ending in an operator (like `1 +`)
+/// continues on the next one, and a line break after a `;` or an empty line doesn't add another statement.
+/// Everywhere else it's ignored like a space.
 pub fn parse(input: &str) -> Result<Vec<Token>, ParseError> {
+	Ok(parse_with_positions(input)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Same as `parse`, but also returns the byte offset in `input` each token starts at.
+/// Implicitly inserted tokens get the position of the token that caused them.
+pub fn parse_with_positions(input: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+	parse_with_prefix(input, DEFAULT_PREFIX)
+}
+
+/// Same as `parse_with_positions`, but function parameters start with `prefix` instead of `$`,
+/// like `@1` for a prefix of `@`. The prefix takes the place of `$`, which is then no longer allowed.
+/// It should be a character that doesn't already mean something else, so not a letter, digit or operator.
+pub fn parse_with_prefix(input: &str, prefix: char) -> Result<Vec<(Token, usize)>, ParseError> {
+	Ok(parse_spans(input, prefix)?.into_iter().map(|(token, start, _)| (token, start)).collect())
+}
+
+/// Same as `parse_with_positions`, but returns the byte offset each token ends at too,
+/// so `&input[start..end]` is the text of the token. Meant for things like syntax highlighting.
+/// Implicitly inserted tokens, like the `*` in `2x`, are empty: they start and end where the token that caused them starts.
+pub fn tokenize(input: &str) -> Result<Vec<(Token, usize, usize)>, ParseError> {
+	parse_spans(input, DEFAULT_PREFIX)
+}
+
+/// Returns the byte offset each line of `input` starts at, leaving out the first line, which starts at 0.
+/// Meant for `Context::line_starts`, so an error on a later line doesn't count its column from the start of the input.
+pub fn line_starts(input: &str) -> Vec<usize> {
+	input.match_indices('\n').map(|(i, _)| i + 1).collect()
+}
+
+fn parse_spans(input: &str, prefix: char) -> Result<Vec<(Token, usize, usize)>, ParseError> {
 	let mut output = Vec::new();
+	let mut positions = Vec::new();
+	let mut ends = Vec::new();
 	let mut buffer = String::new();
+	let mut start = 0;
+	let mut end = 0;
+	// Open bars in the current parenthesis, and in the ones surrounding it
+	let mut bars = 0;
+	let mut outer_bars = Vec::new();
+	let mut bar_closed = false;
 
+	macro_rules! push {
+		($token:expr, $start:expr, $end:expr) => {
+			{
+				output.push($token);
+				positions.push($start);
+				ends.push($end);
+			}
+		}
+	}
 	macro_rules! prepare_var {
-		() => {
+		($pos:expr) => {
 			if let Some(&Token::Num(_)) = output.last() {
-				output.push(Token::Mul);
+				push!(Token::Mul, $pos, $pos);
+			}
+		}
+	}
+	// Whether the last token (or the name or number being read) ends an operand,
+	// so the next one is an operator after it
+	macro_rules! after_operand {
+		() => {
+			!buffer.is_empty() || match output.last() {
+				Some(&Token::Num(_)) |
+				Some(&Token::VarGet(_)) |
+				Some(&Token::ParenClose) |
+				Some(&Token::Factorial) => true,
+				Some(&Token::Bar) => bar_closed,
+				_ => false
 			}
 		}
 	}
 	macro_rules! flush {
 		() => {
 			if !buffer.is_empty() {
-				let buffer = mem::replace(&mut buffer, String::new());
-				match parse_num(&buffer) {
-					Ok(num) => {
-						output.push(Token::Num(num));
+				let buffer = mem::take(&mut buffer);
+				match parse_literal(&buffer)? {
+					Some(num) => {
+						push!(Token::Num(num), start, end);
 					},
-					Err(_) => {
-						prepare_var!();
-						output.push(Token::VarGet(buffer));
+					None => {
+						prepare_var!(start);
+						push!(Token::VarGet(buffer), start, end);
 					}
 				}
 			}
 		}
 	}
 
-	let mut chars = input.chars().enumerate();
+	let mut chars = input.char_indices();
 	while let Some((i, c)) = chars.next() {
 		let token = match c {
-			' ' => continue,
+			' ' => {
+				// `const` and a space before a name make it a constant, like `const k = 5`.
+				// Spaces are ignored everywhere else, so `constant = 5` and `const(k)` stay names.
+				let next = chars.clone().map(|(_, c)| c).find(|&c| c != ' ');
+				let name_follows = matches!(next, Some(c) if c.is_ascii_alphabetic() || c == '_');
+				if buffer == "const" && name_follows {
+					buffer.clear();
+					push!(Token::Const, start, end);
+				}
+				continue;
+			},
+			'#' => {
+				// A comment, which lasts until the end of the statement
+				while let Some((_, c)) = chars.clone().next() {
+					if c == ';' || c == '\n' {
+						break;
+					}
+					chars.next();
+				}
+				continue;
+			},
+			'\r' |
+			'\n' => {
+				if !outer_bars.is_empty() || !after_operand!() {
+					continue;
+				}
+				Some(Token::Semicolon)
+			},
 			',' => Some(Token::Separator),
-			')' => Some(Token::ParenClose),
+			';' => Some(Token::Semicolon),
+			')' => {
+				bars = outer_bars.pop().unwrap_or(0);
+				Some(Token::ParenClose)
+			},
 			'+' => Some(Token::Add),
 			'-' => Some(Token::Sub),
 			'*' => Some(Token::Mul),
-			'/' => Some(Token::Div),
-			'%' => Some(Token::Mod),
-			'&' => Some(Token::And),
-			'|' => Some(Token::Or),
+			'/' => {
+				if let Some((_, '/')) = chars.clone().next() {
+					chars.next();
+					Some(Token::IntDiv)
+				} else {
+					Some(Token::Div)
+				}
+			},
+			'%' => {
+				// A percent sign right after a number, with no second operand
+				// following it, is a percent literal rather than a modulus.
+				flush!();
+				if !followed_by_operand(chars.clone(), prefix) {
+					if let Some(&mut Token::Num(ref mut num)) = output.last_mut() {
+						*num = &*num / BigDecimal::from(100);
+						*ends.last_mut().unwrap() = i + 1;
+						continue;
+					}
+				}
+				Some(Token::Mod)
+			},
+			'&' => {
+				if let Some((_, '&')) = chars.clone().next() {
+					chars.next();
+					Some(Token::LogicalAnd)
+				} else {
+					Some(Token::And)
+				}
+			},
+			'|' => {
+				flush!();
+				let after_operand = after_operand!();
+				if after_operand && bars == 0 {
+					if let Some((_, '|')) = chars.clone().next() {
+						chars.next();
+						Some(Token::LogicalOr)
+					} else {
+						Some(Token::Or)
+					}
+				} else {
+					if after_operand {
+						bars -= 1;
+					} else {
+						bars += 1;
+					}
+					bar_closed = after_operand;
+					Some(Token::Bar)
+				}
+			},
 			'^' => Some(Token::Xor),
 			'<' => {
-				if chars.next() != Some((i+1, '<')) {
-					return Err(ParseError::UnclosedBitShift('<'));
+				match chars.clone().next() {
+					Some((_, '<')) => {
+						chars.next();
+						Some(Token::BitshiftLeft)
+					},
+					Some((_, '=')) => {
+						chars.next();
+						Some(Token::LessEqual)
+					},
+					_ => Some(Token::Less)
 				}
-				Some(Token::BitshiftLeft)
 			},
 			'>' => {
-				if chars.next() != Some((i+1, '>')) {
-					return Err(ParseError::UnclosedBitShift('>'));
+				match chars.clone().next() {
+					Some((_, '>')) => {
+						chars.next();
+						Some(Token::BitshiftRight)
+					},
+					Some((_, '=')) => {
+						chars.next();
+						Some(Token::GreaterEqual)
+					},
+					_ => Some(Token::Greater)
 				}
-				Some(Token::BitshiftRight)
+			},
+			'=' if chars.clone().next().map(|(_, c)| c) == Some('=') => {
+				chars.next();
+				Some(Token::Equal)
 			},
 			'~' => Some(Token::Not),
-			'!' => Some(Token::Factorial),
+			'!' => {
+				if let Some((_, '=')) = chars.clone().next() {
+					chars.next();
+					Some(Token::NotEqual)
+				} else if after_operand!() {
+					Some(Token::Factorial)
+				} else {
+					Some(Token::LogicalNot)
+				}
+			},
 			_   => None
 		};
 
 		if let Some(token) = token {
 			flush!();
-			output.push(token);
+			let end = chars.clone().next().map_or(input.len(), |(j, _)| j);
+			push!(token, i, end);
 		} else if c == '(' {
-			if !buffer.is_empty() {
-				match parse_num(&buffer) {
-					Ok(num) => {
-						output.push(Token::Num(num));
-						output.push(Token::Mul);
+			if buffer.is_empty() {
+				prepare_var!(i);
+			} else {
+				match parse_literal(&buffer)? {
+					Some(num) => {
+						push!(Token::Num(num), start, end);
+						push!(Token::Mul, i, i);
 					},
-					Err(_) => {
-						output.push(Token::BlockName(buffer));
+					None => {
+						prepare_var!(start);
+						push!(Token::BlockName(buffer), start, end);
 					}
 				};
 				buffer = String::new();
 			}
-			output.push(Token::ParenOpen);
+			outer_bars.push(bars);
+			bars = 0;
+			push!(Token::ParenOpen, i, i + 1);
 		} else if c == '=' {
-			let buffer = mem::replace(&mut buffer, String::new());
-			if buffer.is_empty() || is_num(&buffer) || buffer.starts_with('$') || buffer.starts_with('0') {
+			let buffer = mem::take(&mut buffer);
+			if buffer.is_empty() || is_num(&buffer) || looks_like_num(&buffer) || buffer.starts_with(prefix) {
 				return Err(ParseError::DisallowedVariable(buffer));
 			}
-			output.push(Token::VarAssign(buffer));
+			push!(Token::VarAssign(buffer), start, i + 1);
 		} else {
+			if (c == 'e' || c == 'E') && is_num(&buffer) && split_radix(&buffer).0 == 10 {
+				if let Some(exponent) = read_exponent(&buffer, c, &mut chars)? {
+					buffer.push_str(&exponent);
+					end = i + exponent.len();
+					continue;
+				}
+			}
+
 			let code = c as u32;
 			let was_num = is_num(&buffer);
 			let old_len = buffer.len();
 
+			if buffer.is_empty() {
+				start = i;
+			}
 			buffer.push(c);
 			let num = is_num(&buffer);
 			if num ||
 				(code >= 'a' as u32 && code <= 'z' as u32) ||
 				(code >= 'A' as u32 && code <= 'Z' as u32) ||
 				(code >= '0' as u32 && code <= '9' as u32) ||
-				(c == '_' || c == '$') {
+				(c == '_' || c == prefix) {
 
 				if was_num && !num && !buffer.starts_with('0') {
 					buffer.drain(old_len..);
 					flush!();
+					start = i;
 					buffer.push(c);
 				}
+				end = i + c.len_utf8();
 			} else {
 				if c == '.' {
 					return Err(ParseError::DisallowedDecimal);
@@ -203,36 +433,335 @@ pub fn parse(input: &str) -> Result<Vec<Token>, ParseError> {
 
 	flush!();
 
-	Ok(output)
+	Ok(output.into_iter().zip(positions).zip(ends).map(|((token, start), end)| (token, start, end)).collect())
 }
 
+/// Turns tokens back into text that `parse` turns into the same tokens, like `2 * x + 1` for `2x+1`.
+/// The original spacing and implicit multiplications aren't known anymore, so it won't always look like the input did.
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+	let mut output = String::new();
+	let mut previous: Option<&Token> = None;
+	let mut unary = false;
+
+	for token in tokens {
+		let space = match (previous, token) {
+			(None, _) |
+			(Some(&Token::BlockName(_)), _) |
+			(Some(&Token::ParenOpen), _) |
+			(_, &Token::ParenClose) |
+			(_, &Token::Separator) |
+			(_, &Token::Semicolon) |
+			(_, &Token::Factorial) => false,
+			_ => !unary
+		};
+		if space {
+			output.push(' ');
+		}
+		// A sign, `~` or `!` where no operand came before, like `-x`, goes right before its operand
+		unary = match *token {
+			Token::Add | Token::Sub | Token::Not | Token::LogicalNot => !matches!(previous,
+				Some(&Token::Num(_)) |
+				Some(&Token::VarGet(_)) |
+				Some(&Token::ParenClose) |
+				Some(&Token::Bar) |
+				Some(&Token::Factorial)),
+			_ => false
+		};
+
+		match *token {
+			Token::BlockName(ref name) => output.push_str(name),
+			Token::VarAssign(ref name) => {
+				output.push_str(name);
+				output.push_str(" =");
+			},
+			Token::VarGet(ref name) => output.push_str(name),
+			Token::Num(ref num) => output.push_str(&num.to_string()),
+			ref token => output.push_str(match *token {
+				Token::ParenOpen => "(",
+				Token::Separator => ",",
+				Token::Semicolon => ";",
+				Token::ParenClose => ")",
+				Token::Bar => "|",
+				Token::Add => "+",
+				Token::Sub => "-",
+				Token::Mul => "*",
+				Token::Div => "/",
+				Token::IntDiv => "//",
+				Token::Mod => "%",
+				Token::And => "&",
+				Token::Or => "|",
+				Token::Xor => "^",
+				Token::BitshiftLeft => "<<",
+				Token::BitshiftRight => ">>",
+				Token::Not => "~",
+				Token::Factorial => "!",
+				Token::Equal => "==",
+				Token::NotEqual => "!=",
+				Token::Less => "<",
+				Token::LessEqual => "<=",
+				Token::Greater => ">",
+				Token::GreaterEqual => ">=",
+				Token::LogicalAnd => "&&",
+				Token::LogicalOr => "||",
+				Token::LogicalNot => "!",
+				Token::Const => "const",
+				_ => unreachable!()
+			})
+		}
+		previous = Some(token);
+	}
+
+	output
+}
+
+/// Checks if the next non-space character could start an operand.
+/// Used to tell the percent literal (`15%`) apart from a modulus (`15 % 4`, `7 % -3`, `7 % |x|`).
+fn followed_by_operand<I: Iterator<Item = (usize, char)>>(chars: I, prefix: char) -> bool {
+	let mut chars = chars.map(|(_, c)| c).skip_while(|&c| c == ' ');
+	match chars.next() {
+		// `!=` and `||` are operators, not the start of `!x` and `|x|`
+		Some('!') => chars.next() != Some('='),
+		Some('|') => chars.next() != Some('|'),
+		Some(c) => c.is_ascii_alphanumeric() || c == '_' || c == prefix || c == '.' ||
+			c == '(' || c == '~' || c == '-' || c == '+',
+		None => false
+	}
+}
+/// Reads the exponent of a number in scientific notation, like the `e-4` in `2e-4`,
+/// after the `e` itself has been read.
+/// If a name follows it's not an exponent but a name multiplied by the number, so `2exp(1)` is `2 * exp(1)`.
+/// A bare `e`, or a sign without digits (`2e`, `2e+`, `2e-x`) is an invalid number,
+/// write `2 * e` to multiply by the constant.
+fn read_exponent<I>(number: &str, e: char, chars: &mut I) -> Result<Option<String>, ParseError>
+	where I: Iterator<Item = (usize, char)> + Clone {
+
+	let mut exponent = String::new();
+	exponent.push(e);
+
+	let mut rest = chars.clone();
+	if let Some((_, sign @ '+')) | Some((_, sign @ '-')) = rest.clone().next() {
+		rest.next();
+		exponent.push(sign);
+	}
+	let digits: String = rest.clone().map(|(_, c)| c).take_while(|c| c.is_ascii_digit()).collect();
+
+	if digits.is_empty() {
+		let name_follows = matches!(rest.next(), Some((_, c)) if c.is_ascii_alphabetic() || c == '_');
+		if exponent.len() == 1 && name_follows {
+			return Ok(None);
+		}
+		return Err(ParseError::InvalidNumber(format!("{}{}", number, exponent)));
+	}
+
+	exponent.push_str(&digits);
+	for _ in 1..exponent.len() {
+		chars.next();
+	}
+	Ok(Some(exponent))
+}
+/// Turns a word from the input into a number, or `None` if it's a name.
+/// Something that looks like a number but isn't a valid one is an error instead of a name.
+fn parse_literal(word: &str) -> Result<Option<BigDecimal>, ParseError> {
+	match parse_num(word) {
+		Ok(num) => Ok(Some(num)),
+		Err(_) if looks_like_num(word) => Err(ParseError::InvalidNumber(word.to_string())),
+		Err(_) => Ok(None)
+	}
+}
+fn looks_like_num(word: &str) -> bool {
+	// A dot needs digits on at least one side (`.5` and `5.` are fine), so a lone `.` ends up here
+	word.starts_with(|c: char| c.is_ascii_digit() || c == '.') ||
+		(word.contains(|c: char| c.is_ascii_digit()) && word.chars().all(|c| c.is_ascii_digit() || c == '_'))
+}
 fn parse_num(num: &str) -> Result<BigDecimal, ::bigdecimal::ParseBigDecimalError> {
+	use bigdecimal::ParseBigDecimalError;
 	use num::{BigInt, Num};
-	if num.starts_with("0x") {
-		return Ok(BigDecimal::new(BigInt::from_str_radix(&num[2..], 16)?, 0));
-	} else if num.starts_with("0o") {
-		return Ok(BigDecimal::new(BigInt::from_str_radix(&num[2..], 8)?, 0));
-	} else if num.starts_with("0b") {
-		return Ok(BigDecimal::new(BigInt::from_str_radix(&num[2..], 2)?, 0));
+
+	let (radix, digits) = split_radix(num);
+
+	// Underscores are only allowed between two digits, like in `1_000_000`
+	let bytes = digits.as_bytes();
+	for (i, &byte) in bytes.iter().enumerate() {
+		if byte == b'_' {
+			let is_digit = |i: Option<&u8>| i.map(|&c| (c as char).is_digit(radix)).unwrap_or(false);
+			if i == 0 || !is_digit(bytes.get(i - 1)) || !is_digit(bytes.get(i + 1)) {
+				return Err(ParseBigDecimalError::Other(String::from("Misplaced underscore")));
+			}
+		}
 	}
+	let digits: String = digits.chars().filter(|&c| c != '_').collect();
 
-	num.parse()
+	if radix != 10 {
+		return Ok(BigDecimal::new(BigInt::from_str_radix(&digits, radix)?, 0));
+	}
+	if let Some(i) = digits.find(&['e', 'E'][..]) {
+		let exponent: i64 = digits[i + 1..].trim_start_matches('+').parse()
+			.map_err(|_| ParseBigDecimalError::Other(String::from("Exponent too large")))?;
+		if exponent.abs() > MAX_EXPONENT {
+			return Err(ParseBigDecimalError::Other(String::from("Exponent too large")));
+		}
+	}
+	let num: BigDecimal = digits.parse()?;
+	// A large exponent gives a negative scale, which prints 0 as `000`
+	if num.as_bigint_and_exponent().1 < 0 {
+		return Ok(num.with_scale(0));
+	}
+	Ok(num)
 }
-fn is_num(mut num: &str) -> bool {
-	let radix = if num.len() < 2 {
-		10
-	} else {
-		match &num[..2] {
-			"0x" => 16,
-			"0o" => 8,
-			"0b" => 2,
-			_ => 10
+fn is_num(num: &str) -> bool {
+	let (radix, mut num) = split_radix(num);
+
+	if radix == 10 {
+		// Scientific notation, like `1.5e-3`
+		if let Some(i) = num.find(&['e', 'E'][..]) {
+			let mut exponent = &num[i + 1..];
+			if exponent.starts_with('+') || exponent.starts_with('-') {
+				exponent = &exponent[1..];
+			}
+			if exponent.is_empty() || !exponent.bytes().all(|c| c.is_ascii_digit()) {
+				return false;
+			}
+			num = &num[..i];
 		}
-	};
+	}
 
-	if radix != 10 {
-		num = &num[2..];
+	!num.is_empty() && !num.starts_with('_') &&
+		num.chars().all(|c| c.is_digit(radix) || c == '_' || (radix == 10 && c == '.'))
+}
+/// Splits off the radix prefix (`0x`, `0o` or `0b`) of a number, if any
+fn split_radix(num: &str) -> (u32, &str) {
+	if num.len() < 2 {
+		return (10, num);
+	}
+	match &num[..2] {
+		"0x" => (16, &num[2..]),
+		"0o" => (8, &num[2..]),
+		"0b" => (2, &num[2..]),
+		_ => (10, num)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn num(num: &str) -> Token {
+		Token::Num(num.parse().unwrap())
 	}
 
-	!num.is_empty() && num.chars().all(|c| c.is_digit(radix) || (radix == 10 && c == '.'))
+	#[test]
+	fn exponent_without_digits() {
+		for input in &["2e", "2e+", "2E-", "2e-x", "1.5e"] {
+			let number = input.trim_end_matches('x');
+			assert!(matches!(parse(input), Err(ParseError::InvalidNumber(ref n)) if n == number), "{}", input);
+		}
+		// A name starting with `e` is still multiplied
+		assert_eq!(parse("2ex").unwrap(), vec![num("2"), Token::Mul, Token::VarGet("ex".to_string())]);
+		assert_eq!(parse("2e-3").unwrap(), vec![num("0.002")]);
+	}
+	#[test]
+	fn exponent_limit() {
+		assert_eq!(parse("1e10000").unwrap().len(), 1);
+		assert_eq!(parse("1e-10000").unwrap().len(), 1);
+		for input in &["1e10001", "1e-10001", "1e100000000", "1e99999999999999999999"] {
+			assert!(matches!(parse(input), Err(ParseError::InvalidNumber(ref n)) if n == input), "{}", input);
+		}
+	}
+	#[test]
+	fn percent_literal() {
+		assert_eq!(parse("50%").unwrap(), vec![num("0.5")]);
+		assert_eq!(parse("200 * 15%").unwrap(), vec![num("200"), Token::Mul, num("0.15")]);
+		assert_eq!(parse("(10%)").unwrap(), vec![Token::ParenOpen, num("0.1"), Token::ParenClose]);
+		// Between two operands, it's the modulus instead
+		assert_eq!(parse("7 % 3").unwrap(), vec![num("7"), Token::Mod, num("3")]);
+		assert_eq!(parse("7 % (3)").unwrap(), vec![num("7"), Token::Mod, Token::ParenOpen, num("3"), Token::ParenClose]);
+		assert_eq!(parse("7 % -3").unwrap(), vec![num("7"), Token::Mod, Token::Sub, num("3")]);
+		assert_eq!(parse("50% != 1").unwrap(), vec![num("0.5"), Token::NotEqual, num("1")]);
+	}
+	#[test]
+	fn int_div_token() {
+		assert_eq!(parse("7 // 2").unwrap(), vec![num("7"), Token::IntDiv, num("2")]);
+		assert_eq!(parse("7 / 2").unwrap(), vec![num("7"), Token::Div, num("2")]);
+	}
+	#[test]
+	fn digit_separators() {
+		assert_eq!(parse("1_000").unwrap(), vec![num("1000")]);
+		assert_eq!(parse("1_000_000.5").unwrap(), vec![num("1000000.5")]);
+		for input in &["_5", "5_", "1__0", "1_.5", "1._5"] {
+			assert!(matches!(parse(input), Err(ParseError::InvalidNumber(ref number)) if number == input));
+		}
+	}
+	#[test]
+	fn one_sided_decimals() {
+		assert_eq!(parse(".5").unwrap(), vec![num("0.5")]);
+		assert_eq!(parse("5.").unwrap(), vec![num("5")]);
+		assert!(matches!(parse("."), Err(ParseError::InvalidNumber(_))));
+		assert!(matches!(parse("1.2.3"), Err(ParseError::InvalidNumber(_))));
+	}
+	#[test]
+	fn semicolon() {
+		assert_eq!(parse("1; 2").unwrap(), vec![num("1"), Token::Semicolon, num("2")]);
+	}
+	#[test]
+	fn comments() {
+		assert_eq!(parse("2 + 3 # this is five").unwrap(), vec![num("2"), Token::Add, num("3")]);
+		assert_eq!(parse("1 # one; 2").unwrap(), vec![num("1"), Token::Semicolon, num("2")]);
+		assert_eq!(parse("# nothing").unwrap(), vec![]);
+	}
+	#[test]
+	fn param_prefix() {
+		assert_eq!(parse_with_prefix("@1", '@').unwrap(), vec![(Token::VarGet("@1".to_string()), 0)]);
+		assert!(parse_with_prefix("$1", '@').is_err());
+	}
+	#[test]
+	fn scientific_notation() {
+		assert_eq!(parse("1.5e3").unwrap(), vec![num("1500")]);
+		assert_eq!(parse("2E-4").unwrap(), vec![num("0.0002")]);
+		assert_eq!(parse("1e+2").unwrap(), vec![num("100")]);
+		assert_eq!(parse("6.022e23").unwrap(), vec![num("602200000000000000000000")]);
+		assert!(matches!(parse("1e2.5"), Err(ParseError::DisallowedDecimal)));
+	}
+	#[test]
+	fn tokens_round_trip() {
+		for input in &["2x + 1", "f = ($1 * ($2 - 1))", "|x| // 2 % 3", "1 <= x && !y || ~z", "sqrt(2, 5); 50%", "-1.5e3 << 2"] {
+			let tokens = parse(input).unwrap();
+			assert_eq!(parse(&tokens_to_string(&tokens)).unwrap(), tokens, "{}", input);
+		}
+		assert_eq!(tokens_to_string(&parse("2x+1").unwrap()), "2 * x + 1");
+	}
+	#[test]
+	fn not_tokens() {
+		assert_eq!(parse("!~1").unwrap(), vec![Token::LogicalNot, Token::Not, num("1")]);
+		assert_eq!(parse("1 != 2").unwrap(), vec![num("1"), Token::NotEqual, num("2")]);
+	}
+	#[test]
+	fn token_spans() {
+		let input = "12 + 34";
+		let tokens = tokenize(input).unwrap();
+		assert_eq!(tokens, vec![(num("12"), 0, 2), (Token::Add, 3, 4), (num("34"), 5, 7)]);
+		let text: Vec<_> = tokens.iter().map(|&(_, start, end)| &input[start..end]).collect();
+		assert_eq!(text, vec!["12", "+", "34"]);
+
+		// An implicit `*` is empty
+		assert_eq!(tokenize("2x").unwrap()[1], (Token::Mul, 1, 1));
+		assert_eq!(tokenize("sqrt(2)").unwrap()[0], (Token::BlockName("sqrt".to_string()), 0, 4));
+	}
+	#[test]
+	fn const_keyword() {
+		assert_eq!(parse("const k = 5").unwrap(), vec![Token::Const, Token::VarAssign("k".to_string()), num("5")]);
+		assert_eq!(parse("constant = 5").unwrap(), vec![Token::VarAssign("constant".to_string()), num("5")]);
+	}
+	#[test]
+	fn newline_separators() {
+		assert_eq!(parse("1\n2").unwrap(), vec![num("1"), Token::Semicolon, num("2")]);
+		// Inside parentheses, or after another separator, it's just whitespace
+		assert_eq!(parse("(1 +\n2)").unwrap(), vec![Token::ParenOpen, num("1"), Token::Add, num("2"), Token::ParenClose]);
+		assert_eq!(parse("1;\n2").unwrap(), vec![num("1"), Token::Semicolon, num("2")]);
+		assert_eq!(parse("1\n\n2").unwrap(), vec![num("1"), Token::Semicolon, num("2")]);
+	}
+	#[test]
+	fn lines() {
+		assert_eq!(line_starts("1 + 1"), Vec::<usize>::new());
+		assert_eq!(line_starts("x = 1\n\r\ny"), vec![6, 8]);
+	}
 }
@@ -0,0 +1,76 @@
+use bigdecimal::BigDecimal;
+use parser::Token;
+use serde_json;
+use std::collections::HashMap;
+
+/// Serializes a BigDecimal as a string, since a JSON number would lose precision
+pub mod decimal {
+	use bigdecimal::BigDecimal;
+	use serde::de::Error;
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::str::FromStr;
+
+	pub fn serialize<S: Serializer>(num: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&num.to_string())
+	}
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+		let string = String::deserialize(deserializer)?;
+		BigDecimal::from_str(&string).map_err(|_| D::Error::custom(format!("invalid number \"{}\"", string)))
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct Decimal(#[serde(with = "decimal")] BigDecimal);
+
+#[derive(Serialize, Deserialize)]
+struct State {
+	variables: HashMap<String, Decimal>,
+	functions: HashMap<String, Vec<Token>>
+}
+
+/// Turns variables and functions into JSON, so a session can be saved and loaded later with `load_state`
+pub fn save_state(
+		variables: &HashMap<String, BigDecimal>,
+		functions: &HashMap<String, Vec<Token>>
+	) -> serde_json::Result<String> {
+
+	let state = State {
+		variables: variables.iter().map(|(name, val)| (name.clone(), Decimal(val.clone()))).collect(),
+		functions: functions.clone()
+	};
+	serde_json::to_string(&state)
+}
+/// Variables and functions, as returned by `load_state`
+pub type Session = (HashMap<String, BigDecimal>, HashMap<String, Vec<Token>>);
+
+/// Reads variables and functions from JSON made by `save_state`
+pub fn load_state(json: &str) -> serde_json::Result<Session> {
+	let state: State = serde_json::from_str(json)?;
+	let variables = state.variables.into_iter().map(|(name, Decimal(val))| (name, val)).collect();
+	Ok((variables, state.functions))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let mut variables = HashMap::new();
+		let mut functions = HashMap::new();
+		::parse_and_calc("x = 0.1000; big = 123456789012345678901234567890.5; f = ($1 * x)", &mut variables, &mut functions).unwrap();
+
+		let json = save_state(&variables, &functions).unwrap();
+		let (mut loaded_variables, mut loaded_functions) = load_state(&json).unwrap();
+		assert_eq!(loaded_variables, variables);
+		assert_eq!(loaded_functions, functions);
+		assert_eq!(loaded_variables["x"].to_string(), "0.1000");
+
+		let result = ::parse_and_calc("f(big)", &mut loaded_variables, &mut loaded_functions).unwrap();
+		assert_eq!(result, "12345678901234567890123456789.05".parse::<BigDecimal>().unwrap());
+	}
+	#[test]
+	fn invalid_number() {
+		assert!(load_state(r#"{"variables": {"x": "abc"}, "functions": {}}"#).is_err());
+	}
+}
@@ -7,17 +7,42 @@ use bigdecimal::BigDecimal;
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 use simple_math_lib::*;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{env, mem, thread};
+
+/// How much stack the calculations get. Every parenthesis and function call recurses,
+/// and going as deep as the calculator allows (until `TooDeep`) takes more than the main thread has.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Everything kept from one input to the next
+pub struct Session {
+	pub variables: HashMap<String, BigDecimal>,
+	pub functions: HashMap<String, Vec<parser::Token>>,
+	/// The names that are read-only, like from `const k = 5`, see `calculator::Context::protected`
+	pub protected: HashSet<String>,
+	/// The earlier results, most recent first, for `ans(n)`, see `calculator::Context::history`
+	pub history: VecDeque<BigDecimal>,
+	/// What went wrong with the last input, or `None` if it succeeded, for `:error`
+	pub last_error: Option<calculator::CalcError>
+}
 
 fn main() {
+	let repl = thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap();
+	repl.join().unwrap();
+}
+fn run() {
 	let mut terminate = false;
-	let mut variables = HashMap::new();
-	variables.insert("out".to_string(), BigDecimal::from(10));
-	let mut functions = HashMap::new();
+	let mut session = Session {
+		variables: HashMap::new(),
+		functions: HashMap::new(),
+		protected: HashSet::new(),
+		history: VecDeque::new(),
+		last_error: None
+	};
+	session.variables.insert("out".to_string(), BigDecimal::from(10));
 
 	for arg in env::args().skip(1) {
-		if let Some(output) = calculate(&arg, &mut variables, &mut functions) {
+		if let Some(output) = calculate(&arg, &mut session) {
 			println!("{}", output);
 		}
 		terminate = true;
@@ -43,29 +68,83 @@ fn main() {
 			continue;
 		}
 		rl.add_history_entry(&input);
-		if let Some(output) = calculate(&input, &mut variables, &mut functions) {
+		if let Some(output) = command(&input, &session) {
+			println!("{}", output);
+			continue;
+		}
+		if let Some(output) = calculate(&input, &mut session) {
 			println!("= {}", output);
 		}
 	}
 }
 
-pub fn calculate(
-		input: &str,
-		variables: &mut HashMap<String, BigDecimal>,
-		functions: &mut HashMap<String, Vec<parser::Token>>
-	) -> Option<String> {
+/// Same as `parse_and_calc`, but with the read-only names and the history of `session` too,
+/// so a `const` stays read-only on later inputs and `ans` is the last result
+fn parse_and_calc_session(input: &str, session: &mut Session) -> calculator::CalcResult<BigDecimal> {
+	let (tokens, mut positions): (Vec<_>, Vec<_>) = match parser::parse_with_positions(input) {
+		Ok(tokens) => tokens.into_iter().unzip(),
+		Err(err) => {
+			session.last_error = Some(err.clone().into());
+			return Err(err.into());
+		}
+	};
+	positions.push(input.len());
+
+	let mut context = calculator::Context::new(tokens.into_iter().peekable(), &mut session.variables, &mut session.functions);
+	context.positions = positions;
+	context.line_starts = parser::line_starts(input);
+	context.protected.extend(session.protected.drain());
+	context.history = Some(&mut session.history);
+	let result = calculator::calculate_all(&mut context);
+	session.protected = mem::take(&mut context.protected);
+	session.last_error = context.last_error().cloned();
+
+	if let Some(last) = session.history.front() {
+		session.variables.insert("ans".to_string(), last.clone());
+	}
+	result
+}
+
+/// Runs `input` if it's a REPL command instead of a calculation, and returns what to show.
+/// `:error` shows the error of the last input again.
+pub fn command(input: &str, session: &Session) -> Option<String> {
+	match input.trim() {
+		":error" => Some(match session.last_error {
+			Some(ref err) => format!("Last error: {}", err),
+			None => "No error".to_string()
+		}),
+		_ => None
+	}
+}
+
+pub fn calculate(input: &str, session: &mut Session) -> Option<String> {
 	use num::ToPrimitive;
-	match parse_and_calc(input, variables, functions) {
+	let result = parse_and_calc_session(input, session);
+	let variables = &session.variables;
+	match result {
 		Ok(result) => {
 			use num::Zero;
 			use num::bigint::ToBigInt;
 			if result.is_zero() {
 				return None;
 			}
+			if variables.get("fraction").map(|fraction| !fraction.is_zero()).unwrap_or(false) {
+				use num::One;
+				let (numerator, denominator) = calculator::to_fraction(&result);
+				if denominator.is_one() {
+					return Some(numerator.to_string());
+				}
+				return Some(format!("{}/{}", numerator, denominator));
+			}
 			match variables.get("out").unwrap().to_u8() {
 				Some(2)  => return Some(format!("{:b}", result.to_bigint().unwrap())),
 				Some(8)  => return Some(format!("{:o}", result.to_bigint().unwrap())),
-				Some(10) => return Some(result.to_string()),
+				Some(10) => {
+					if variables.get("trim_trailing_zeros").map(|trim| !trim.is_zero()).unwrap_or(false) {
+						return Some(format::normalize_output(&result).to_string());
+					}
+					return Some(result.to_string())
+				},
 				Some(16) => return Some(format!("{:X}", result.to_bigint().unwrap())),
 				_  => {
 					eprintln!("Warning: Unsupported \"out\" variable value");
@@ -77,3 +156,75 @@ pub fn calculate(
 	}
 	None
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn session() -> Session {
+		let mut session = Session {
+			variables: HashMap::new(),
+			functions: HashMap::new(),
+			protected: HashSet::new(),
+			history: VecDeque::new(),
+			last_error: None
+		};
+		session.variables.insert("out".to_string(), BigDecimal::from(10));
+		session
+	}
+
+	#[test]
+	fn const_stays_read_only_on_later_lines() {
+		let mut session = session();
+		assert_eq!(calculate("const k = 5", &mut session), Some("5".to_string()));
+		assert_eq!(calculate("k = 6", &mut session), None);
+		assert_eq!(calculate("k * 2", &mut session), Some("10".to_string()));
+	}
+	#[test]
+	fn results_are_kept_as_ans() {
+		let mut session = session();
+		assert_eq!(calculate("2 + 3", &mut session), Some("5".to_string()));
+		assert_eq!(calculate("x = 7", &mut session), Some("7".to_string()));
+		assert_eq!(calculate("ans * 2", &mut session), Some("10".to_string()));
+		assert_eq!(calculate("ans(2) + ans(1)", &mut session), Some("15".to_string()));
+		assert_eq!(session.history.len(), 3);
+	}
+	#[test]
+	fn error_command_shows_last_error() {
+		let mut session = session();
+		assert_eq!(command(":error", &session), Some("No error".to_string()));
+		assert_eq!(calculate("1 / 0", &mut session), None);
+		assert!(matches!(session.last_error, Some(calculator::CalcError::DivideByZero)));
+		assert_eq!(command(":error", &session), Some(format!("Last error: {}", calculator::CalcError::DivideByZero)));
+		assert_eq!(calculate("1 + 1", &mut session), Some("2".to_string()));
+		assert_eq!(command(":error", &session), Some("No error".to_string()));
+		assert_eq!(command("1 + 1", &session), None);
+		assert_eq!(calculate("1 @ 1", &mut session), None);
+		assert!(matches!(session.last_error, Some(calculator::CalcError::ParseError(_))));
+		assert_eq!(calculate("x = 1\n x + y", &mut session), None);
+		assert_eq!(command(":error", &session), Some("Last error: Unknown variable \"y\" at line 2, column 6".to_string()));
+	}
+	#[test]
+	fn percent_output_matches_readme() {
+		let mut session = session();
+		assert_eq!(calculate("200 * 15%", &mut session), Some("30.00".to_string()));
+		assert_eq!(calculate("100 + 10%", &mut session), Some("100.1".to_string()));
+		calculate("trim_trailing_zeros = 1", &mut session);
+		assert_eq!(calculate("200 * 15%", &mut session), Some("30".to_string()));
+	}
+	#[test]
+	fn fraction_output() {
+		let mut session = session();
+		calculate("fraction = 1", &mut session);
+		assert_eq!(calculate("0.75", &mut session), Some("3/4".to_string()));
+		assert_eq!(calculate("6 / 2", &mut session), Some("3".to_string()));
+	}
+	#[test]
+	fn trimmed_output() {
+		let mut session = session();
+		assert_eq!(calculate("2.500", &mut session), Some("2.500".to_string()));
+		calculate("trim_trailing_zeros = 1", &mut session);
+		assert_eq!(calculate("2.500", &mut session), Some("2.5".to_string()));
+		assert_eq!(calculate("100", &mut session), Some("100".to_string()));
+	}
+}